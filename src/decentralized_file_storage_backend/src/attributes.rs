@@ -0,0 +1,127 @@
+// Typed custom attributes on files, such as `project` or `reviewed_by`. Attributes are
+// searchable: equality is looked up in an index, ranges are checked per file.
+use crate::patch::MetadataPatch;
+use crate::{FileMetadata, StorageError, STATE};
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::update;
+
+pub(crate) const MAX_ATTRIBUTES: usize = 32;
+const MAX_KEY_LENGTH: usize = 64;
+const MAX_TEXT_LENGTH: usize = 1024;
+
+#[derive(CandidType, Clone, Deserialize, PartialEq)]
+pub(crate) enum AttributeValue {
+    Text(String),
+    Number(f64),
+    Bool(bool),
+    Timestamp(u64),
+}
+
+#[derive(CandidType, Clone, Deserialize)]
+pub(crate) struct FileAttribute {
+    pub(crate) key: String,
+    pub(crate) value: AttributeValue,
+}
+
+// Inclusive bounds on an attribute's value. Only values of the bounds' type match; with
+// no bounds, any file that has the attribute matches.
+#[derive(CandidType, Clone, Deserialize)]
+pub(crate) struct AttributeRange {
+    key: String,
+    min: Option<AttributeValue>,
+    max: Option<AttributeValue>,
+}
+
+impl AttributeValue {
+    // Orders values of the same type; None for values of different types
+    fn compare(&self, other: &AttributeValue) -> Option<std::cmp::Ordering> {
+        match (self, other) {
+            (AttributeValue::Text(a), AttributeValue::Text(b)) => Some(a.cmp(b)),
+            (AttributeValue::Number(a), AttributeValue::Number(b)) => a.partial_cmp(b),
+            (AttributeValue::Bool(a), AttributeValue::Bool(b)) => Some(a.cmp(b)),
+            (AttributeValue::Timestamp(a), AttributeValue::Timestamp(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+}
+
+impl AttributeRange {
+    pub(crate) fn matches(&self, metadata: &FileMetadata) -> bool {
+        let value = match metadata.attributes.iter().find(|entry| entry.key == self.key) {
+            Some(entry) => &entry.value,
+            None => return false,
+        };
+        let above_min = self
+            .min
+            .as_ref()
+            .is_none_or(|min| value.compare(min).is_some_and(|order| order.is_ge()));
+        let below_max = self
+            .max
+            .as_ref()
+            .is_none_or(|max| value.compare(max).is_some_and(|order| order.is_le()));
+        above_min && below_max
+    }
+}
+
+impl FileAttribute {
+    pub(crate) fn matches(&self, metadata: &FileMetadata) -> bool {
+        metadata
+            .attributes
+            .iter()
+            .any(|entry| entry.key == self.key && entry.value == self.value)
+    }
+}
+
+// Key under which an attribute is stored in the attribute index. Equal values give
+// equal keys; the NUL separator cannot occur in attribute keys.
+pub(crate) fn attribute_index_key(attribute: &FileAttribute) -> String {
+    let value = match &attribute.value {
+        AttributeValue::Text(text) => format!("t:{}", text),
+        // Adding 0.0 turns -0.0 into 0.0, which compares equal to it
+        AttributeValue::Number(number) => format!("n:{:016x}", (number + 0.0).to_bits()),
+        AttributeValue::Bool(flag) => format!("b:{}", flag),
+        AttributeValue::Timestamp(timestamp) => format!("s:{}", timestamp),
+    };
+    format!("{}\0{}", attribute.key, value)
+}
+
+pub(crate) fn valid_attribute_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LENGTH && !key.chars().any(char::is_control)
+}
+
+pub(crate) fn validate_attribute(attribute: &FileAttribute) -> Result<(), StorageError> {
+    let valid_key = valid_attribute_key(&attribute.key);
+    let valid_value = match &attribute.value {
+        AttributeValue::Text(text) => text.len() <= MAX_TEXT_LENGTH,
+        AttributeValue::Number(number) => number.is_finite(),
+        AttributeValue::Bool(_) | AttributeValue::Timestamp(_) => true,
+    };
+    if valid_key && valid_value {
+        Ok(())
+    } else {
+        Err(StorageError::InvalidOperation)
+    }
+}
+
+// Sets the attributes in `set`, replacing existing values, and removes those named in
+// `remove`. Other attributes are left alone. A key may appear only once across both.
+#[update]
+fn update_file_attributes(
+    name: String,
+    set: Vec<FileAttribute>,
+    remove: Vec<String>,
+    expected_revision: Option<u64>,
+) -> Result<bool, StorageError> {
+    let caller = ic_cdk::caller();
+    let patch = MetadataPatch {
+        set_attributes: Some(set),
+        remove_attributes: Some(remove),
+        ..Default::default()
+    };
+    STATE.with(|state| {
+        state
+            .borrow_mut()
+            .patch_metadata(caller, &name, patch, expected_revision)?;
+        Ok(true)
+    })
+}
@@ -0,0 +1,127 @@
+// Files with an expiry time. Once it passes they are hidden from reads right away,
+// and a timer deletes them for good, skipping the trash.
+use crate::listing::FilePosition;
+use crate::{get_current_timestamp, http, FileMetadata, FileStorage, StorageError, STATE};
+use std::cell::RefCell;
+use std::ops::Bound;
+use std::time::Duration;
+
+// How often the timer looks for expired files
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// Expired entries checked per timer tick, so a tick stays within the instruction limit
+const MAX_EXPIRY_BATCH: usize = 1000;
+
+thread_local! {
+    // Where the next tick resumes, so retained files at the front of the expiry
+    // index do not keep the timer from reaching the ones behind them. Lives on the
+    // heap; after an upgrade the sweep starts over.
+    static SWEEP_CURSOR: RefCell<Option<FilePosition>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn is_expired(metadata: &FileMetadata) -> bool {
+    metadata
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= get_current_timestamp())
+}
+
+// An expiry must lie in the future
+pub(crate) fn validate_expiry(expires_at: Option<u64>) -> Result<(), StorageError> {
+    match expires_at {
+        Some(expires_at) if expires_at <= get_current_timestamp() => {
+            Err(StorageError::InvalidOperation)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn expiry_position(metadata: &FileMetadata) -> Option<FilePosition> {
+    metadata.expires_at.map(|expires_at| FilePosition {
+        value: expires_at,
+        name: metadata.name.clone(),
+    })
+}
+
+impl FileStorage {
+    pub(crate) fn index_expiry(&mut self, metadata: &FileMetadata) {
+        if let Some(position) = expiry_position(metadata) {
+            self.expiry_index.insert(position, ());
+        }
+    }
+
+    pub(crate) fn unindex_expiry(&mut self, metadata: &FileMetadata) {
+        if let Some(position) = expiry_position(metadata) {
+            self.expiry_index.remove(&position);
+        }
+    }
+
+    // Metadata of a file that exists and has not expired
+    pub(crate) fn unexpired_file(&self, name: &String) -> Result<FileMetadata, StorageError> {
+        match self.files.get(name) {
+            Some(metadata) if !is_expired(&metadata) => Ok(metadata),
+            _ => Err(StorageError::FileNotFound),
+        }
+    }
+
+    // Deletes a file for good, skipping the trash and freeing chunks nothing else
+    // refers to
+    fn delete_now(&mut self, name: &String) {
+        if let Some(metadata) = self.take_file(name) {
+            http::uncertify_file(name);
+            self.release_versions(&metadata);
+        }
+    }
+
+    // Frees the name of an expired file that the timer has not deleted yet, so a new
+    // file can take its place. A retained file keeps the name: RetentionActive.
+    pub(crate) fn free_expired_name(&mut self, name: &String) -> Result<(), StorageError> {
+        match self.files.get(name) {
+            Some(metadata) if is_expired(&metadata) => {
+                self.check_retention(&metadata)?;
+                self.delete_now(name);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Checks up to MAX_EXPIRY_BATCH expired files, soonest expiry first, starting
+    // where the previous tick stopped, and deletes them. Retained files stay, hidden,
+    // until their retention lapses.
+    fn delete_expired(&mut self) -> usize {
+        let now = get_current_timestamp();
+        let start = match SWEEP_CURSOR.with(|cursor| cursor.borrow_mut().take()) {
+            Some(position) => Bound::Excluded(position),
+            None => Bound::Unbounded,
+        };
+        let expired: Vec<FilePosition> = self
+            .expiry_index
+            .keys_range((start, Bound::Unbounded))
+            .take_while(|position| position.value <= now)
+            .take(MAX_EXPIRY_BATCH)
+            .collect();
+        if expired.len() == MAX_EXPIRY_BATCH {
+            let last = expired.last().cloned();
+            SWEEP_CURSOR.with(|cursor| *cursor.borrow_mut() = last);
+        }
+
+        let mut deleted = 0;
+        for position in &expired {
+            let unretained = self
+                .files
+                .get(&position.name)
+                .is_some_and(|metadata| self.check_retention(&metadata).is_ok());
+            if unretained {
+                self.delete_now(&position.name);
+                deleted += 1;
+            }
+        }
+        deleted
+    }
+}
+
+// Timers do not survive upgrades, so this runs on init and post_upgrade
+pub(crate) fn start_expiry_timer() {
+    ic_cdk_timers::set_timer_interval(EXPIRY_INTERVAL, || {
+        STATE.with(|state| state.borrow_mut().delete_expired());
+    });
+}
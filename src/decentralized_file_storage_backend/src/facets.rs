@@ -0,0 +1,149 @@
+// Aggregates for building search filters: facet counts over the results of a query,
+// and tag autocomplete. Both only count files the caller can read.
+use crate::expiry::is_expired;
+use crate::search::FileQuery;
+use crate::{can_access, ShareRole, StorageError, STATE};
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::query;
+use std::collections::HashMap;
+
+// Matching files examined per facet query
+const MAX_FACET_SCAN: usize = 10_000;
+// Tag and file type values returned per facet, most frequent first
+const MAX_FACET_VALUES: usize = 100;
+// Lower bounds of the size buckets after the first, which starts at 0
+const SIZE_BUCKET_BOUNDS: &[u64] = &[1024, 64 * 1024, 1024 * 1024];
+const DEFAULT_SUGGESTIONS: u32 = 10;
+const MAX_SUGGESTIONS: u32 = 50;
+// Tag postings examined per suggest_tags call
+const MAX_SUGGEST_SCAN: usize = 10_000;
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct FacetCount {
+    value: String,
+    count: u64,
+}
+
+// Files with `min` <= size < `max`; no `max` means unbounded
+#[derive(CandidType, Deserialize)]
+pub(crate) struct SizeBucket {
+    min: u64,
+    max: Option<u64>,
+    count: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct SearchFacets {
+    total: u64,
+    tags: Vec<FacetCount>,
+    file_types: Vec<FacetCount>,
+    sizes: Vec<SizeBucket>,
+    truncated: bool, // Only the first MAX_FACET_SCAN candidates were counted
+}
+
+// The most frequent values first, ties in value order
+fn top_counts(counts: HashMap<String, u64>, limit: usize) -> Vec<FacetCount> {
+    let mut counts: Vec<FacetCount> = counts
+        .into_iter()
+        .map(|(value, count)| FacetCount { value, count })
+        .collect();
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+    counts.truncate(limit);
+    counts
+}
+
+fn size_buckets() -> Vec<SizeBucket> {
+    let mins = std::iter::once(0).chain(SIZE_BUCKET_BOUNDS.iter().copied());
+    let maxes = SIZE_BUCKET_BOUNDS.iter().copied().map(Some).chain(std::iter::once(None));
+    mins.zip(maxes)
+        .map(|(min, max)| SizeBucket { min, max, count: 0 })
+        .collect()
+}
+
+// Counts per tag, per file type and per size bucket over the readable files matching
+// `query`
+#[query]
+fn search_facets(query: FileQuery) -> Result<SearchFacets, StorageError> {
+    query.validate(0)?;
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let storage = state.borrow();
+
+        let mut total = 0;
+        let mut tags = HashMap::new();
+        let mut file_types = HashMap::new();
+        let mut sizes = size_buckets();
+
+        let mut candidates = storage.candidates(&query, None);
+        for position in candidates.by_ref().take(MAX_FACET_SCAN) {
+            let metadata = match storage.files.get(&position.name) {
+                Some(metadata) => metadata,
+                None => continue,
+            };
+            if !can_access(caller, &metadata, ShareRole::Read)
+                || is_expired(&metadata)
+                || !query.matches(&metadata)
+            {
+                continue;
+            }
+
+            total += 1;
+            let mut seen = metadata.tags.clone();
+            seen.sort();
+            seen.dedup();
+            for tag in seen {
+                *tags.entry(tag).or_insert(0) += 1;
+            }
+            *file_types.entry(metadata.file_type.clone()).or_insert(0) += 1;
+            let size = metadata.size as u64;
+            if let Some(bucket) = sizes
+                .iter_mut()
+                .find(|bucket| size >= bucket.min && bucket.max.is_none_or(|max| size < max))
+            {
+                bucket.count += 1;
+            }
+        }
+
+        Ok(SearchFacets {
+            total,
+            tags: top_counts(tags, MAX_FACET_VALUES),
+            file_types: top_counts(file_types, MAX_FACET_VALUES),
+            sizes,
+            truncated: candidates.next().is_some(),
+        })
+    })
+}
+
+// Tags starting with `prefix`, most used first, counting only files the caller can read
+#[query]
+fn suggest_tags(prefix: String, limit: Option<u32>) -> Vec<FacetCount> {
+    let caller = ic_cdk::caller();
+    let limit = limit
+        .unwrap_or(DEFAULT_SUGGESTIONS)
+        .clamp(1, MAX_SUGGESTIONS) as usize;
+    STATE.with(|state| {
+        let storage = state.borrow();
+
+        let mut counts = HashMap::new();
+        let mut budget = MAX_SUGGEST_SCAN;
+        for (tag, _) in storage.tag_index.keys_with_prefix(&prefix) {
+            if budget == 0 {
+                break;
+            }
+            let names: Vec<String> = storage.tag_index.names(&tag, None).take(budget).collect();
+            budget -= names.len();
+
+            let readable = names
+                .iter()
+                .filter_map(|name| storage.files.get(name))
+                .filter(|metadata| {
+                    can_access(caller, metadata, ShareRole::Read) && !is_expired(metadata)
+                })
+                .count() as u64;
+            if readable > 0 {
+                counts.insert(tag, readable);
+            }
+        }
+        top_counts(counts, limit)
+    })
+}
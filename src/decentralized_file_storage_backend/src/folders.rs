@@ -0,0 +1,414 @@
+// Hierarchical namespace. Files and folders are keyed by their full normalized path
+// ("docs/reports/q1.pdf"); the root folder is the empty path and always exists.
+use crate::expiry::is_expired;
+use crate::listing::{page_limit, FileSummary, MAX_PAGE_BYTES, MAX_SCAN};
+use crate::{
+    authenticated_caller, authorize_access, can_access, get_current_timestamp, FileStorage, Memory,
+    ShareRole, StorageError, STATE,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::ops::Bound;
+
+const MAX_PATH_LENGTH: usize = 1024;
+const MAX_SEGMENT_LENGTH: usize = 255;
+// Files and folders removed per delete_folder call, so a call stays within the
+// instruction limit
+const MAX_DELETE_BATCH: usize = 1000;
+
+#[derive(CandidType, Clone, Deserialize)]
+pub(crate) struct FolderMetadata {
+    path: String,
+    owner: Principal,
+    created_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct FolderListing {
+    path: String,
+    folders: Vec<FolderMetadata>,
+    files: Vec<FileSummary>,
+    next_cursor: Option<String>, // Path of the last child examined; None once complete
+}
+
+// Normalizes a user-supplied path: leading, trailing and repeated slashes are dropped.
+// Rejects "." and ".." segments, control characters and over-long paths.
+pub(crate) fn normalize_path(path: &str) -> Result<String, StorageError> {
+    let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+
+    for segment in &segments {
+        if *segment == "."
+            || *segment == ".."
+            || segment.len() > MAX_SEGMENT_LENGTH
+            || segment.chars().any(char::is_control)
+        {
+            return Err(StorageError::InvalidPath);
+        }
+    }
+
+    let normalized = segments.join("/");
+    if normalized.len() > MAX_PATH_LENGTH {
+        return Err(StorageError::InvalidPath);
+    }
+    Ok(normalized)
+}
+
+// Normalizes a path that must name a file or folder, i.e. not the root
+pub(crate) fn normalize_entry_path(path: &str) -> Result<String, StorageError> {
+    let normalized = normalize_path(path)?;
+    if normalized.is_empty() {
+        return Err(StorageError::InvalidPath);
+    }
+    Ok(normalized)
+}
+
+// Every proper ancestor of a normalized path, outermost first ("a", "a/b" for "a/b/c")
+fn ancestors(path: &str) -> impl Iterator<Item = &str> {
+    path.match_indices('/').map(move |(index, _)| &path[..index])
+}
+
+// First path in `paths` from `start` on that is a direct child of the folder with
+// `prefix`. Deeper paths are skipped a whole subfolder at a time, by seeking past
+// everything that starts with "<child>/". Each lookup counts against `seeks`.
+fn next_child<V: Storable>(
+    paths: &StableBTreeMap<String, V, Memory>,
+    prefix: &str,
+    mut start: Bound<String>,
+    seeks: &mut usize,
+) -> Option<String> {
+    loop {
+        *seeks += 1;
+        let path = paths
+            .keys_range((start, Bound::Unbounded))
+            .next()
+            .filter(|path| path.starts_with(prefix))?;
+        match path[prefix.len()..].find('/') {
+            None => return Some(path),
+            // '0' is the character after '/'
+            Some(slash) => {
+                start = Bound::Included(format!("{}0", &path[..prefix.len() + slash]));
+            }
+        }
+    }
+}
+
+// Prefix shared by every path inside a folder
+fn child_prefix(folder: &str) -> String {
+    if folder.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", folder)
+    }
+}
+
+impl FileStorage {
+    // Makes `path` usable for a new file or folder: see check_path. Missing ancestor
+    // folders are created.
+    pub(crate) fn prepare_path(&mut self, path: &str, caller: Principal) -> Result<(), StorageError> {
+        self.check_path(path, caller)?;
+        self.create_ancestors(path, caller);
+        Ok(())
+    }
+
+    // Whether `caller` may create a file or folder at `path`: nothing may already exist
+    // at `path` itself, no ancestor may be a file, and the innermost existing folder
+    // above it must be the caller's. Folders are not shared, so only their owner and
+    // controllers can add entries to them.
+    pub(crate) fn check_path(&self, path: &str, caller: Principal) -> Result<(), StorageError> {
+        if self.folders.contains_key(&path.to_string()) {
+            return Err(StorageError::FileAlreadyExists);
+        }
+        let mut parent = None;
+        for ancestor in ancestors(path) {
+            if self.files.contains_key(&ancestor.to_string()) {
+                return Err(StorageError::InvalidPath);
+            }
+            if let Some(folder) = self.folders.get(&ancestor.to_string()) {
+                parent = Some(folder);
+            }
+        }
+        match parent {
+            Some(folder) if folder.owner != caller && !ic_cdk::api::is_controller(&caller) => {
+                Err(StorageError::Unauthorized)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Creates the missing ancestor folders of a path that passed check_path
+    pub(crate) fn create_ancestors(&mut self, path: &str, caller: Principal) {
+        for ancestor in ancestors(path) {
+            if !self.folders.contains_key(&ancestor.to_string()) {
+                self.folders.insert(
+                    ancestor.to_string(),
+                    FolderMetadata {
+                        path: ancestor.to_string(),
+                        owner: caller,
+                        created_at: get_current_timestamp(),
+                    },
+                );
+            }
+        }
+    }
+
+    // Up to `limit` file paths inside a folder, at any depth
+    fn files_under(&self, folder: &str, limit: usize) -> Vec<String> {
+        let prefix = child_prefix(folder);
+        self.files
+            .keys_range(prefix.clone()..)
+            .take_while(|path| path.starts_with(&prefix))
+            .take(limit)
+            .collect()
+    }
+
+    // Up to `limit` folder paths inside a folder, at any depth
+    fn folders_under(&self, folder: &str, limit: usize) -> Vec<String> {
+        let prefix = child_prefix(folder);
+        self.folders
+            .keys_range(prefix.clone()..)
+            .take_while(|path| path.starts_with(&prefix))
+            .take(limit)
+            .collect()
+    }
+}
+
+#[update]
+fn create_folder(path: String) -> Result<bool, StorageError> {
+    let caller = authenticated_caller()?;
+    let path = normalize_entry_path(&path)?;
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+
+        if storage.files.contains_key(&path) {
+            return Err(StorageError::FileAlreadyExists);
+        }
+        storage.prepare_path(&path, caller)?;
+        storage.folders.insert(
+            path.clone(),
+            FolderMetadata {
+                path,
+                owner: caller,
+                created_at: get_current_timestamp(),
+            },
+        );
+        Ok(true)
+    })
+}
+
+// One page of the direct children of a folder, folders and files together in path
+// order. Files the caller cannot read are left out. Like list_files, a page may hold
+// fewer than `limit` children while `next_cursor` is still set.
+#[query]
+fn list_folder(
+    path: String,
+    cursor: Option<String>,
+    limit: Option<u32>,
+) -> Result<FolderListing, StorageError> {
+    let caller = ic_cdk::caller();
+    let path = normalize_path(&path)?;
+    let limit = page_limit(limit);
+    STATE.with(|state| {
+        let storage = state.borrow();
+
+        if !path.is_empty() && !storage.folders.contains_key(&path) {
+            return Err(StorageError::FolderNotFound);
+        }
+
+        let prefix = child_prefix(&path);
+        let start = match cursor {
+            Some(cursor) if cursor.starts_with(&prefix) => Bound::Excluded(cursor),
+            _ => Bound::Included(prefix.clone()),
+        };
+        let mut seeks = 0;
+        let mut next_folder = next_child(&storage.folders, &prefix, start.clone(), &mut seeks);
+        let mut next_file = next_child(&storage.files, &prefix, start, &mut seeks);
+
+        let mut folders = Vec::new();
+        let mut files = Vec::new();
+        let mut last = None;
+        let mut bytes = 0;
+        while folders.len() + files.len() < limit && seeks < MAX_SCAN && bytes < MAX_PAGE_BYTES
+        {
+            let folder_first = match (&next_folder, &next_file) {
+                (Some(folder), Some(file)) => folder < file,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            let child = if folder_first {
+                let child = next_folder.take().unwrap_or_default();
+                if let Some(folder) = storage.folders.get(&child) {
+                    bytes += 64 + child.len();
+                    folders.push(folder);
+                }
+                next_folder = next_child(
+                    &storage.folders,
+                    &prefix,
+                    Bound::Excluded(child.clone()),
+                    &mut seeks,
+                );
+                child
+            } else {
+                let child = next_file.take().unwrap_or_default();
+                if let Some(metadata) = storage.files.get(&child) {
+                    if can_access(caller, &metadata, ShareRole::Read) && !is_expired(&metadata) {
+                        let summary = FileSummary::from(metadata);
+                        bytes += summary.encoded_size();
+                        files.push(summary);
+                    }
+                }
+                next_file = next_child(
+                    &storage.files,
+                    &prefix,
+                    Bound::Excluded(child.clone()),
+                    &mut seeks,
+                );
+                child
+            };
+            last = Some(child);
+        }
+
+        let more = next_folder.is_some() || next_file.is_some();
+        Ok(FolderListing {
+            path,
+            folders,
+            files,
+            next_cursor: if more { last } else { None },
+        })
+    })
+}
+
+// Deletes a folder with everything in it; the files go to the trash. Large folders are
+// emptied over several calls, at most 1000 entries each: the call returns false while
+// content remains and true once the folder itself is gone, so repeat it until true.
+// The caller must own the folder, and needs Admin rights on every file and must own
+// every subfolder a call removes; no such file may be retained. A call that fails
+// removes nothing, but files trashed by earlier calls stay in the trash.
+#[update]
+fn delete_folder(path: String) -> Result<bool, StorageError> {
+    let caller = ic_cdk::caller();
+    let path = normalize_entry_path(&path)?;
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+
+        let folder = storage.folders.get(&path).ok_or(StorageError::FolderNotFound)?;
+        let is_controller = ic_cdk::api::is_controller(&caller);
+        if folder.owner != caller && !is_controller {
+            return Err(StorageError::Unauthorized);
+        }
+
+        // Files go first, so no file is ever left in a folder that no longer exists
+        let files = storage.files_under(&path, MAX_DELETE_BATCH);
+        let folders = storage.folders_under(&path, MAX_DELETE_BATCH - files.len());
+        for name in &files {
+            if let Some(metadata) = storage.files.get(name) {
+                authorize_access(caller, &metadata, ShareRole::Admin)?;
+                storage.check_retention(&metadata)?;
+            }
+        }
+        for folder_path in &folders {
+            let owned = storage
+                .folders
+                .get(folder_path)
+                .is_some_and(|child| child.owner == caller);
+            if !owned && !is_controller {
+                return Err(StorageError::Unauthorized);
+            }
+        }
+
+        for name in &files {
+            storage.trash_file(caller, name);
+        }
+        if files.len() == MAX_DELETE_BATCH {
+            return Ok(false);
+        }
+        for folder_path in &folders {
+            storage.folders.remove(folder_path);
+        }
+        if files.len() + folders.len() == MAX_DELETE_BATCH {
+            return Ok(false);
+        }
+        storage.folders.remove(&path);
+        Ok(true)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    use ic_stable_structures::DefaultMemoryImpl;
+
+    fn children(paths: &[&str], folder: &str) -> (Vec<String>, usize) {
+        let memory = MemoryManager::init(DefaultMemoryImpl::default()).get(MemoryId::new(0));
+        let mut map: StableBTreeMap<String, (), Memory> = StableBTreeMap::init(memory);
+        for path in paths {
+            map.insert(path.to_string(), ());
+        }
+        let prefix = child_prefix(folder);
+        let mut seeks = 0;
+        let mut found = Vec::new();
+        let mut start = Bound::Included(prefix.clone());
+        while let Some(child) = next_child(&map, &prefix, start, &mut seeks) {
+            start = Bound::Excluded(child.clone());
+            found.push(child);
+        }
+        (found, seeks)
+    }
+
+    #[test]
+    fn finds_direct_children_only() {
+        let paths = ["a", "a/x", "a/y/z", "a!", "a0", "b", "b/c/d", "bb", "c/d"];
+        let (found, _) = children(&paths, "");
+        assert_eq!(found, vec!["a", "a!", "a0", "b", "bb"]);
+        let (found, _) = children(&paths, "a");
+        assert_eq!(found, vec!["a/x"]);
+        let (found, _) = children(&paths, "b/c");
+        assert_eq!(found, vec!["b/c/d"]);
+    }
+
+    #[test]
+    fn skips_a_subfolder_in_one_seek() {
+        let mut paths: Vec<String> = (0..100).map(|i| format!("deep/{:03}", i)).collect();
+        paths.push("top".to_string());
+        let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        let (found, seeks) = children(&paths, "");
+        assert_eq!(found, vec!["top"]);
+        // One seek lands in "deep/", one skips it, one finds nothing after "top"
+        assert_eq!(seeks, 3);
+    }
+
+    #[test]
+    fn normalizes_slashes() {
+        assert_eq!(normalize_path("/docs//reports/q1.pdf/").unwrap(), "docs/reports/q1.pdf");
+        assert_eq!(normalize_path("").unwrap(), "");
+        assert_eq!(normalize_path("///").unwrap(), "");
+    }
+
+    #[test]
+    fn rejects_dot_segments_and_control_characters() {
+        assert!(normalize_path("docs/../secret").is_err());
+        assert!(normalize_path("..").is_err());
+        assert!(normalize_path("docs/./a").is_err());
+        assert!(normalize_path("docs/a\nb").is_err());
+        // Dots inside a name are fine
+        assert_eq!(normalize_path("a/..b/c.").unwrap(), "a/..b/c.");
+    }
+
+    #[test]
+    fn rejects_over_long_paths() {
+        let segment = "a".repeat(MAX_SEGMENT_LENGTH);
+        assert!(normalize_path(&segment).is_ok());
+        assert!(normalize_path(&format!("{}a", segment)).is_err());
+
+        let path = vec!["a"; MAX_PATH_LENGTH / 2 + 1].join("/");
+        assert!(normalize_path(&path).is_err());
+    }
+
+    #[test]
+    fn entry_paths_exclude_the_root() {
+        assert!(normalize_entry_path("/").is_err());
+        assert_eq!(normalize_entry_path("a/").unwrap(), "a");
+    }
+}
@@ -0,0 +1,505 @@
+// Full-text search over the live content of textual files. Content is split into
+// lowercase alphanumeric terms; the index records the position of every term in every
+// file so quoted phrases can be matched, and is updated whenever a file is stored.
+use crate::expiry::is_expired;
+use crate::{
+    can_access, live_version, FileMetadata, FileStorage, Memory, ShareRole, Sha256Hash,
+    StorageError, STATE,
+};
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::query;
+use ic_stable_structures::StableBTreeMap;
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
+
+// Only the start of large files is indexed, so indexing fits in one update call
+const MAX_INDEXED_BYTES: usize = 1024 * 1024;
+// Longer tokens (encoded data, hashes) are skipped but still take up a position
+const MAX_TERM_LENGTH: usize = 64;
+const MAX_QUERY_TERMS: usize = 16;
+// Files containing the rarest query term that are examined per query
+const MAX_CANDIDATES: usize = 10_000;
+const DEFAULT_HITS: u32 = 20;
+const MAX_HITS: u32 = 100;
+const SNIPPET_CONTEXT: usize = 80; // Bytes of content on each side of the match
+
+// File types treated as text, besides text/*
+const TEXTUAL_TYPES: &[&str] = &[
+    "application/json",
+    "application/xml",
+    "application/javascript",
+    "application/yaml",
+    "application/x-yaml",
+    "application/toml",
+];
+
+#[derive(CandidType, Clone, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct TermPosting {
+    term: String,
+    name: String,
+}
+
+// Token positions of one term in one file, ascending
+#[derive(CandidType, Clone, Deserialize)]
+pub(crate) struct TermPositions {
+    positions: Vec<u32>,
+    // Byte offset of the first occurrence, for snippets; None if indexed before
+    // offsets were recorded, or if the content is not UTF-8 and offsets into the
+    // decoded text would not match the stored bytes
+    first_offset: Option<u32>,
+}
+
+// What is indexed for a file, so it can be unindexed without reading the content
+#[derive(CandidType, Clone, Deserialize)]
+pub(crate) struct TextDocument {
+    sha256: Sha256Hash, // Also kept in `hashes`, which is cheaper to read
+    length: u32, // Number of tokens
+    terms: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct TextSearchHit {
+    name: String,
+    score: f64,
+    snippet: String,
+}
+
+pub(crate) struct TextIndex {
+    postings: StableBTreeMap<TermPosting, TermPositions, Memory>,
+    documents: StableBTreeMap<String, TextDocument, Memory>,
+    frequencies: StableBTreeMap<String, u64, Memory>, // Files containing each term
+    hashes: StableBTreeMap<String, Sha256Hash, Memory>, // Content hash of each document
+}
+
+pub(crate) fn is_textual(file_type: &str) -> bool {
+    let mime = file_type.split(';').next().unwrap_or_default().trim();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || TEXTUAL_TYPES.contains(&mime)
+}
+
+// Alphanumeric runs of `text` with their byte offsets
+fn tokens(text: &str) -> Vec<(usize, &str)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (offset, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(offset),
+            (false, Some(from)) => {
+                tokens.push((from, &text[from..offset]));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(from) = start {
+        tokens.push((from, &text[from..]));
+    }
+    tokens
+}
+
+// Splits a query into phrases: quoted parts are phrases, every other term is a
+// phrase of its own
+fn parse_query(query: &str) -> Vec<Vec<String>> {
+    let mut phrases = Vec::new();
+    for (i, part) in query.split('"').enumerate() {
+        let terms: Vec<String> = tokens(part)
+            .into_iter()
+            .map(|(_, token)| token.to_lowercase())
+            .collect();
+        if i % 2 == 1 {
+            if !terms.is_empty() {
+                phrases.push(terms);
+            }
+        } else {
+            phrases.extend(terms.into_iter().map(|term| vec![term]));
+        }
+    }
+    phrases
+}
+
+// Whether the terms of `phrase` occur at consecutive positions
+fn contains_phrase(phrase: &[String], positions: &HashMap<&String, Vec<u32>>) -> bool {
+    positions[&phrase[0]].iter().any(|start| {
+        phrase.iter().enumerate().skip(1).all(|(offset, term)| {
+            positions[term]
+                .binary_search(&(start + offset as u32))
+                .is_ok()
+        })
+    })
+}
+
+// Whether decoding `content` lossily keeps every byte offset: true for valid UTF-8,
+// and for content cut off inside a character, whose replacement comes last. Invalid
+// bytes elsewhere become the 3-byte U+FFFD and shift every offset after them.
+fn replaces_only_tail(content: &[u8]) -> bool {
+    match std::str::from_utf8(content) {
+        Ok(_) => true,
+        Err(error) => error.error_len().is_none(),
+    }
+}
+
+// Text of a byte range cut from UTF-8 content, without the partial characters at
+// either end
+fn window_text(bytes: &[u8]) -> String {
+    let start = bytes
+        .iter()
+        .position(|byte| byte & 0xC0 != 0x80)
+        .unwrap_or(bytes.len());
+    let bytes = &bytes[start..];
+    let bytes = match std::str::from_utf8(bytes) {
+        Err(error) if error.error_len().is_none() => &bytes[..error.valid_up_to()],
+        _ => bytes,
+    };
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+// Collapses whitespace and marks text cut off before or after the snippet
+fn tidy_snippet(text: &str, cut_before: bool, cut_after: bool) -> String {
+    let mut snippet = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if cut_before {
+        snippet.insert(0, '…');
+    }
+    if cut_after {
+        snippet.push('…');
+    }
+    snippet
+}
+
+// Up to SNIPPET_CONTEXT bytes of text on each side of byte `offset`
+fn snippet(text: &str, offset: usize) -> String {
+    let mut start = offset.saturating_sub(SNIPPET_CONTEXT);
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = (offset + SNIPPET_CONTEXT).min(text.len());
+    while !text.is_char_boundary(end) {
+        end += 1;
+    }
+
+    tidy_snippet(&text[start..end], start > 0, end < text.len())
+}
+
+impl TextIndex {
+    pub(crate) fn init(
+        postings: Memory,
+        documents: Memory,
+        frequencies: Memory,
+        hashes: Memory,
+    ) -> Self {
+        TextIndex {
+            postings: StableBTreeMap::init(postings),
+            documents: StableBTreeMap::init(documents),
+            frequencies: StableBTreeMap::init(frequencies),
+            hashes: StableBTreeMap::init(hashes),
+        }
+    }
+
+    // Hash of the content a file was indexed with. Documents indexed before hashes were
+    // kept apart only have it in the document itself.
+    fn indexed_hash(&self, name: &String) -> Option<Sha256Hash> {
+        self.hashes
+            .get(name)
+            .or_else(|| self.documents.get(name).map(|document| document.sha256))
+    }
+
+    fn frequency(&self, term: &str) -> u64 {
+        self.frequencies.get(&term.to_string()).unwrap_or(0)
+    }
+
+    fn positions(&self, term: &str, name: &str) -> Option<Vec<u32>> {
+        let posting = TermPosting {
+            term: term.to_string(),
+            name: name.to_string(),
+        };
+        self.postings.get(&posting).map(|entry| entry.positions)
+    }
+
+    // `raw_offsets` says whether byte offsets into `text` are also offsets into the
+    // stored content; if not, no offsets are recorded and snippets retokenize
+    fn add_document(&mut self, name: &str, sha256: Sha256Hash, text: &str, raw_offsets: bool) {
+        let tokens = tokens(text);
+        let mut positions: HashMap<String, TermPositions> = HashMap::new();
+        for (position, (offset, token)) in tokens.iter().enumerate() {
+            if token.len() <= MAX_TERM_LENGTH {
+                positions
+                    .entry(token.to_lowercase())
+                    .or_insert_with(|| TermPositions {
+                        positions: Vec::new(),
+                        first_offset: Some(*offset as u32).filter(|_| raw_offsets),
+                    })
+                    .positions
+                    .push(position as u32);
+            }
+        }
+
+        let terms: Vec<String> = positions.keys().cloned().collect();
+        for (term, positions) in positions {
+            self.frequencies.insert(term.clone(), self.frequency(&term) + 1);
+            let posting = TermPosting {
+                term,
+                name: name.to_string(),
+            };
+            self.postings.insert(posting, positions);
+        }
+        self.hashes.insert(name.to_string(), sha256);
+        self.documents.insert(
+            name.to_string(),
+            TextDocument {
+                sha256,
+                length: tokens.len() as u32,
+                terms,
+            },
+        );
+    }
+
+    pub(crate) fn remove_document(&mut self, name: &String) {
+        self.hashes.remove(name);
+        let document = match self.documents.remove(name) {
+            Some(document) => document,
+            None => return,
+        };
+        for term in document.terms {
+            match self.frequency(&term) {
+                0 | 1 => self.frequencies.remove(&term),
+                count => self.frequencies.insert(term.clone(), count - 1),
+            };
+            self.postings.remove(&TermPosting {
+                term,
+                name: name.clone(),
+            });
+        }
+    }
+}
+
+impl FileStorage {
+    // The indexed part of a file's live content
+    fn indexed_text(&self, metadata: &FileMetadata) -> Option<String> {
+        let content = self.indexed_content(metadata)?;
+        Some(String::from_utf8_lossy(&content).into_owned())
+    }
+
+    fn indexed_content(&self, metadata: &FileMetadata) -> Option<Vec<u8>> {
+        let mut content = Vec::new();
+        for hash in &live_version(metadata).ok()?.chunks {
+            if content.len() >= MAX_INDEXED_BYTES {
+                break;
+            }
+            content.extend_from_slice(&self.read_chunk(hash)?);
+        }
+        content.truncate(MAX_INDEXED_BYTES);
+        Some(content)
+    }
+
+    // Snippet around byte `offset` of a file's live content, reading only the chunks
+    // the snippet lies in. None unless `term` starts at `offset`, which fails for
+    // offsets recorded from non-UTF-8 content before such offsets were dropped.
+    fn snippet_at(&self, metadata: &FileMetadata, offset: usize, term: &str) -> Option<String> {
+        let indexed = metadata.size.min(MAX_INDEXED_BYTES);
+        let start = offset.saturating_sub(SNIPPET_CONTEXT);
+        let end = (offset + SNIPPET_CONTEXT).min(indexed);
+        if start >= end || offset >= end {
+            return None;
+        }
+        let bytes = self
+            .read_version_range(live_version(metadata).ok()?, start, end)
+            .ok()?;
+        let at = bytes
+            .get(offset - start..)
+            .filter(|at| at.first().is_some_and(|byte| byte & 0xC0 != 0x80))?;
+        let found = tokens(&window_text(at))
+            .first()
+            .is_some_and(|(position, token)| *position == 0 && token.to_lowercase() == term);
+        if !found {
+            return None;
+        }
+        Some(tidy_snippet(&window_text(&bytes), start > 0, end < indexed))
+    }
+
+    // Snippet for a hit whose posting predates recorded offsets: finds the offset by
+    // tokenizing the indexed text
+    fn snippet_at_position(&self, metadata: &FileMetadata, position: u32) -> Option<String> {
+        let text = self.indexed_text(metadata)?;
+        let offset = tokens(&text).get(position as usize)?.0;
+        Some(snippet(&text, offset))
+    }
+
+    // Brings the text index up to date with a file that is being stored. Only
+    // reindexes when the live content or the file's textual status changed.
+    pub(crate) fn index_text(&mut self, metadata: &FileMetadata) {
+        let textual = is_textual(&metadata.file_type);
+        match self.text_index.indexed_hash(&metadata.name) {
+            Some(sha256) if textual && sha256 == metadata.sha256 => return,
+            Some(_) => self.text_index.remove_document(&metadata.name),
+            None => {}
+        }
+
+        if textual {
+            if let Some(content) = self.indexed_content(metadata) {
+                let text = String::from_utf8_lossy(&content);
+                self.text_index.add_document(
+                    &metadata.name,
+                    metadata.sha256,
+                    &text,
+                    replaces_only_tail(&content),
+                );
+            }
+        }
+    }
+}
+
+// Ranked full-text search over the textual files the caller can read. Every term must
+// occur in a file; "quoted phrases" must occur as written. Scores use TF-IDF with
+// sublinear term frequency, damped by document length.
+#[query]
+fn search_text(query: String, limit: Option<u32>) -> Result<Vec<TextSearchHit>, StorageError> {
+    let caller = ic_cdk::caller();
+    let phrases = parse_query(&query);
+    let terms: Vec<&String> = phrases
+        .iter()
+        .flatten()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    if terms.is_empty() || terms.len() > MAX_QUERY_TERMS {
+        return Err(StorageError::InvalidOperation);
+    }
+    let limit = limit.unwrap_or(DEFAULT_HITS).clamp(1, MAX_HITS) as usize;
+
+    STATE.with(|state| {
+        let storage = state.borrow();
+        let index = &storage.text_index;
+        let documents = index.documents.len() as f64;
+
+        // Walk the files containing the rarest term and look up the others in each
+        let rarest = terms
+            .iter()
+            .min_by_key(|term| index.frequency(term))
+            .map(|term| term.to_string())
+            .unwrap_or_default();
+        let start = TermPosting {
+            term: rarest.clone(),
+            name: String::new(),
+        };
+        let candidates = index
+            .postings
+            .range((Bound::Included(start), Bound::Unbounded))
+            .take_while(|(posting, _)| posting.term == rarest)
+            .take(MAX_CANDIDATES);
+
+        let mut hits = Vec::new();
+        'candidates: for (posting, first) in candidates {
+            let mut positions = HashMap::new();
+            for term in &terms {
+                let found = if **term == rarest {
+                    Some(first.positions.clone())
+                } else {
+                    index.positions(term, &posting.name)
+                };
+                match found {
+                    Some(found) => positions.insert(*term, found),
+                    None => continue 'candidates,
+                };
+            }
+            if !phrases.iter().all(|phrase| contains_phrase(phrase, &positions)) {
+                continue;
+            }
+            let readable = storage
+                .files
+                .get(&posting.name)
+                .is_some_and(|metadata| {
+                    can_access(caller, &metadata, ShareRole::Read) && !is_expired(&metadata)
+                });
+            if !readable {
+                continue;
+            }
+
+            let length = index
+                .documents
+                .get(&posting.name)
+                .map_or(1, |document| document.length.max(1));
+            let score = positions
+                .iter()
+                .map(|(term, found)| {
+                    let tf = 1.0 + (found.len() as f64).ln();
+                    let idf = (1.0 + documents / index.frequency(term).max(1) as f64).ln();
+                    tf * idf
+                })
+                .sum::<f64>()
+                / (1.0 + (length as f64).ln());
+            hits.push((score, posting.name, first.first_offset, first.positions[0]));
+        }
+
+        hits.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        hits.truncate(limit);
+        Ok(hits
+            .into_iter()
+            .map(|(score, name, offset, position)| {
+                let snippet = storage
+                    .files
+                    .get(&name)
+                    .and_then(|metadata| {
+                        offset
+                            .and_then(|offset| {
+                                storage.snippet_at(&metadata, offset as usize, &rarest)
+                            })
+                            .or_else(|| storage.snippet_at_position(&metadata, position))
+                    })
+                    .unwrap_or_default();
+                TextSearchHit {
+                    name,
+                    score,
+                    snippet,
+                }
+            })
+            .collect())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_alphanumeric_runs_with_offsets() {
+        assert_eq!(
+            tokens("Hello, wörld 42!"),
+            vec![(0, "Hello"), (7, "wörld"), (14, "42")]
+        );
+        assert_eq!(tokens("--"), Vec::<(usize, &str)>::new());
+        assert_eq!(tokens(""), Vec::<(usize, &str)>::new());
+    }
+
+    #[test]
+    fn keeps_offsets_only_for_utf8_content() {
+        assert!(replaces_only_tail("café".as_bytes()));
+        // Cut off inside "é": the replacement comes last
+        assert!(replaces_only_tail(&"café".as_bytes()[..4]));
+        // Latin-1 "café"
+        assert!(!replaces_only_tail(b"caf\xe9 au lait"));
+    }
+
+    #[test]
+    fn parses_terms_and_quoted_phrases() {
+        assert_eq!(
+            parse_query("Annual \"Sales Report\" 2024"),
+            vec![
+                vec!["annual".to_string()],
+                vec!["sales".to_string(), "report".to_string()],
+                vec!["2024".to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn handles_empty_and_unclosed_quotes() {
+        assert_eq!(parse_query("\"\" a"), vec![vec!["a".to_string()]]);
+        // An unclosed quote still starts a phrase
+        assert_eq!(
+            parse_query("x \"b c"),
+            vec![vec!["x".to_string()], vec!["b".to_string(), "c".to_string()]]
+        );
+        assert!(parse_query("  ").is_empty());
+    }
+}
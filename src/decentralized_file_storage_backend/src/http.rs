@@ -0,0 +1,446 @@
+// HTTP gateway so browsers can fetch files from /files/<name>. Responses carry an
+// IC-Certificate header so boundary nodes can verify them against the certified data.
+use crate::expiry::is_expired;
+use crate::{
+    can_access, find_version, live_version, FileStorage, FileVersion, ShareRole, Sha256Hash,
+    CHUNK_SIZE, STATE,
+};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use candid::{define_function, CandidType, Deserialize};
+use ic_cdk_macros::query;
+use ic_certified_map::{labeled, labeled_hash, AsHashTree, RbTree};
+use serde::Serialize;
+use std::cell::RefCell;
+
+const FILES_PATH_PREFIX: &str = "/files/";
+// Label under which boundary nodes look up certified response bodies
+const LABEL_ASSETS: &[u8] = b"http_assets";
+
+type HeaderField = (String, String);
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<HeaderField>,
+    body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct HttpResponse {
+    status_code: u16,
+    headers: Vec<HeaderField>,
+    body: Vec<u8>,
+    streaming_strategy: Option<StreamingStrategy>,
+}
+
+// Identifies the remaining bytes [offset, end) to stream; pinned to a version so a
+// concurrent update cannot mix content from two versions in one response
+#[derive(CandidType, Deserialize, Clone)]
+pub(crate) struct StreamingCallbackToken {
+    name: String,
+    version: u64,
+    offset: usize,
+    end: usize,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct StreamingCallbackHttpResponse {
+    body: Vec<u8>,
+    token: Option<StreamingCallbackToken>,
+}
+
+define_function!(StreamingCallback : (StreamingCallbackToken) -> (StreamingCallbackHttpResponse) query);
+
+#[derive(CandidType, Deserialize)]
+enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallback,
+        token: StreamingCallbackToken,
+    },
+}
+
+thread_local! {
+    // Certified SHA-256 of every file body, keyed by request path. Lives on the heap and
+    // is rebuilt from stable storage after an upgrade.
+    static ASSET_HASHES: RefCell<RbTree<String, Sha256Hash>> = const { RefCell::new(RbTree::new()) };
+}
+
+fn file_path(name: &str) -> String {
+    format!("{}{}", FILES_PATH_PREFIX, name)
+}
+
+fn update_certified_data() {
+    ASSET_HASHES.with(|hashes| {
+        let root_hash = labeled_hash(LABEL_ASSETS, &hashes.borrow().root_hash());
+        ic_cdk::api::set_certified_data(&root_hash);
+    });
+}
+
+// Certifies the live content of a file. Must be called from update calls whenever
+// a file's live content changes.
+pub(crate) fn certify_file(name: &str, sha256: &Sha256Hash) {
+    ASSET_HASHES.with(|hashes| hashes.borrow_mut().insert(file_path(name), *sha256));
+    update_certified_data();
+}
+
+pub(crate) fn uncertify_file(name: &str) {
+    ASSET_HASHES.with(|hashes| hashes.borrow_mut().delete(file_path(name).as_bytes()));
+    update_certified_data();
+}
+
+pub(crate) fn certify_all(storage: &FileStorage) {
+    ASSET_HASHES.with(|hashes| {
+        let mut hashes = hashes.borrow_mut();
+        for (name, metadata) in storage.files.iter() {
+            hashes.insert(file_path(&name), metadata.sha256);
+        }
+    });
+    update_certified_data();
+}
+
+fn certificate_header(path: &str) -> Option<HeaderField> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    ASSET_HASHES.with(|hashes| {
+        let hashes = hashes.borrow();
+        let tree = labeled(LABEL_ASSETS, hashes.witness(path.as_bytes()));
+
+        let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+        serializer.self_describe().ok()?;
+        tree.serialize(&mut serializer).ok()?;
+
+        Some((
+            "IC-Certificate".to_string(),
+            format!(
+                "certificate=:{}:, tree=:{}:",
+                BASE64.encode(certificate),
+                BASE64.encode(serializer.into_inner())
+            ),
+        ))
+    })
+}
+
+// Decodes %XX escapes in a URL path; returns None for malformed escapes or non-UTF-8 results
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Byte range selected by the request's Range header; `end` is exclusive
+enum ByteRange {
+    Full,
+    Partial { start: usize, end: usize },
+    Unsatisfiable,
+}
+
+// Parses a single `Range: bytes=...` header. Malformed or multi-range headers are
+// ignored and the whole file is served, as RFC 9110 allows.
+fn requested_range(headers: &[HeaderField], size: usize) -> ByteRange {
+    let value = match headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("range"))
+    {
+        Some((_, value)) => value.trim(),
+        None => return ByteRange::Full,
+    };
+    let spec = match value.strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Full,
+    };
+
+    if first.is_empty() {
+        // Suffix range: the last N bytes
+        return match last.parse::<usize>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(suffix) if size > 0 => ByteRange::Partial {
+                start: size.saturating_sub(suffix),
+                end: size,
+            },
+            Ok(_) => ByteRange::Unsatisfiable,
+            Err(_) => ByteRange::Full,
+        };
+    }
+
+    let start = match first.parse::<usize>() {
+        Ok(start) => start,
+        Err(_) => return ByteRange::Full,
+    };
+    let end = if last.is_empty() {
+        size
+    } else {
+        match last.parse::<usize>() {
+            Ok(last) if last >= start => last.saturating_add(1).min(size),
+            _ => return ByteRange::Full,
+        }
+    };
+
+    if start >= size {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial { start, end }
+    }
+}
+
+// Reads from `offset` up to `end` or the next chunk boundary, whichever comes first,
+// so every response or callback carries at most one chunk
+fn read_span(storage: &FileStorage, version: &FileVersion, offset: usize, end: usize) -> Option<Vec<u8>> {
+    if offset >= end {
+        return None;
+    }
+    let index = offset / CHUNK_SIZE;
+    let chunk_start = index * CHUNK_SIZE;
+    let chunk = storage.read_chunk(version.chunks.get(index)?)?;
+    let to = (end - chunk_start).min(chunk.len());
+    chunk.get(offset - chunk_start..to).map(|span| span.to_vec())
+}
+
+// Types a browser would run as script or markup in the canister's origin. Files of
+// these types are served as downloads, so an uploader cannot use them to act on
+// behalf of whoever opens the link.
+const ACTIVE_CONTENT_TYPES: &[&str] = &[
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/xml",
+    "application/xml",
+    "text/javascript",
+    "application/javascript",
+];
+
+// Compares the media type without parameters such as `; charset=utf-8`
+fn is_active_content(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    ACTIVE_CONTENT_TYPES
+        .iter()
+        .any(|active| essence.eq_ignore_ascii_case(active))
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn error_response(status_code: u16, message: &str) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+        body: message.as_bytes().to_vec(),
+        streaming_strategy: None,
+    }
+}
+
+#[query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return error_response(405, "Method not allowed");
+    }
+
+    let path = request.url.split('?').next().unwrap_or_default();
+    let name = match path
+        .strip_prefix(FILES_PATH_PREFIX)
+        .and_then(percent_decode)
+    {
+        Some(name) if !name.is_empty() => name,
+        _ => return error_response(404, "Not found"),
+    };
+
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let storage = state.borrow();
+        let metadata = match storage.unexpired_file(&name) {
+            Ok(metadata) => metadata,
+            Err(_) => return error_response(404, "Not found"),
+        };
+        if !can_access(caller, &metadata, ShareRole::Read) {
+            return error_response(403, "Forbidden");
+        }
+        let live = match live_version(&metadata) {
+            Ok(live) => live,
+            Err(_) => return error_response(500, "Internal error"),
+        };
+
+        let content_type = if metadata.file_type.is_empty() {
+            "application/octet-stream".to_string()
+        } else {
+            metadata.file_type.clone()
+        };
+        let active = is_active_content(&content_type);
+        let mut headers = vec![
+            ("Content-Type".to_string(), content_type),
+            ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+            ("Accept-Ranges".to_string(), "bytes".to_string()),
+            ("ETag".to_string(), format!("\"{}\"", hex_encode(&metadata.sha256))),
+        ];
+        if active {
+            headers.push(("Content-Disposition".to_string(), "attachment".to_string()));
+        }
+
+        // Certification covers the whole body, so only full responses carry a certificate;
+        // range requests need to go through the raw domain.
+        let (status_code, start, end) = match requested_range(&request.headers, metadata.size) {
+            ByteRange::Full => {
+                if let Some(certificate) = certificate_header(&file_path(&name)) {
+                    headers.push(certificate);
+                }
+                (200, 0, metadata.size)
+            }
+            ByteRange::Partial { start, end } => {
+                headers.push((
+                    "Content-Range".to_string(),
+                    format!("bytes {}-{}/{}", start, end - 1, metadata.size),
+                ));
+                (206, start, end)
+            }
+            ByteRange::Unsatisfiable => {
+                let mut response = error_response(416, "Range not satisfiable");
+                response.headers.push((
+                    "Content-Range".to_string(),
+                    format!("bytes */{}", metadata.size),
+                ));
+                return response;
+            }
+        };
+        headers.push(("Content-Length".to_string(), (end - start).to_string()));
+
+        // The first chunk's worth goes in the response, the rest through the streaming callback
+        let body = if start < end {
+            match read_span(&storage, live, start, end) {
+                Some(body) => body,
+                None => return error_response(500, "Internal error"),
+            }
+        } else {
+            Vec::new()
+        };
+        let next_offset = start + body.len();
+        let streaming_strategy = (next_offset < end).then(|| StreamingStrategy::Callback {
+            callback: StreamingCallback::new(
+                ic_cdk::id(),
+                "http_request_streaming_callback".to_string(),
+            ),
+            token: StreamingCallbackToken {
+                name: name.clone(),
+                version: live.version,
+                offset: next_offset,
+                end,
+            },
+        });
+
+        HttpResponse {
+            status_code,
+            headers,
+            body,
+            streaming_strategy,
+        }
+    })
+}
+
+#[query]
+fn http_request_streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let storage = state.borrow();
+        let body = storage
+            .files
+            .get(&token.name)
+            .filter(|metadata| {
+                can_access(caller, metadata, ShareRole::Read) && !is_expired(metadata)
+            })
+            .and_then(|metadata| {
+                let version = find_version(&metadata, token.version).ok()?;
+                read_span(&storage, version, token.offset, token.end)
+            })
+            .filter(|body| !body.is_empty());
+
+        match body {
+            Some(body) => {
+                let next_offset = token.offset + body.len();
+                StreamingCallbackHttpResponse {
+                    body,
+                    token: (next_offset < token.end).then_some(StreamingCallbackToken {
+                        offset: next_offset,
+                        ..token
+                    }),
+                }
+            }
+            None => ic_cdk::trap("File changed or removed while streaming"),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(value: &str, size: usize) -> ByteRange {
+        requested_range(&[("Range".to_string(), value.to_string())], size)
+    }
+
+    fn is_partial(range: ByteRange, expected: (usize, usize)) -> bool {
+        matches!(range, ByteRange::Partial { start, end } if (start, end) == expected)
+    }
+
+    #[test]
+    fn parses_bounded_and_open_ranges() {
+        assert!(is_partial(range("bytes=0-99", 1000), (0, 100)));
+        assert!(is_partial(range("bytes=900-", 1000), (900, 1000)));
+        // An end past the file is clamped to its size
+        assert!(is_partial(range("bytes=900-5000", 1000), (900, 1000)));
+        assert!(matches!(range("bytes=1000-", 1000), ByteRange::Unsatisfiable));
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert!(is_partial(range("bytes=-100", 1000), (900, 1000)));
+        // A suffix longer than the file selects all of it
+        assert!(is_partial(range("bytes=-5000", 1000), (0, 1000)));
+        assert!(matches!(range("bytes=-0", 1000), ByteRange::Unsatisfiable));
+    }
+
+    #[test]
+    fn empty_files_satisfy_no_range() {
+        assert!(matches!(range("bytes=0-", 0), ByteRange::Unsatisfiable));
+        assert!(matches!(range("bytes=-1", 0), ByteRange::Unsatisfiable));
+        assert!(matches!(requested_range(&[], 0), ByteRange::Full));
+    }
+
+    #[test]
+    fn ignores_malformed_and_multiple_ranges() {
+        assert!(matches!(range("bytes=0-1,5-6", 1000), ByteRange::Full));
+        assert!(matches!(range("bytes=5-1", 1000), ByteRange::Full));
+        assert!(matches!(range("items=0-1", 1000), ByteRange::Full));
+        assert!(matches!(range("bytes=a-b", 1000), ByteRange::Full));
+        assert!(matches!(range("bytes=-x", 1000), ByteRange::Full));
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("docs/a%20b.txt").as_deref(), Some("docs/a b.txt"));
+        assert_eq!(percent_decode("caf%C3%A9").as_deref(), Some("café"));
+        assert_eq!(percent_decode("plain").as_deref(), Some("plain"));
+    }
+
+    #[test]
+    fn rejects_bad_percent_escapes() {
+        assert_eq!(percent_decode("a%2"), None);
+        assert_eq!(percent_decode("a%"), None);
+        assert_eq!(percent_decode("a%zz"), None);
+        // Decodes to bytes that are not UTF-8
+        assert_eq!(percent_decode("%C3%28"), None);
+    }
+}
@@ -0,0 +1,101 @@
+// Inverted indexes over file metadata. Each maps a key (a tag, a file type, an owner)
+// to the names of the files that have it, and counts the files per key.
+use crate::Memory;
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::StableBTreeMap;
+use std::ops::Bound;
+
+// One entry of a posting list: file `name` has `key`
+#[derive(CandidType, Clone, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Posting {
+    key: String,
+    name: String,
+}
+
+pub(crate) struct PostingIndex {
+    postings: StableBTreeMap<Posting, (), Memory>,
+    counts: StableBTreeMap<String, u64, Memory>,
+}
+
+impl PostingIndex {
+    pub(crate) fn init(postings: Memory, counts: Memory) -> Self {
+        PostingIndex {
+            postings: StableBTreeMap::init(postings),
+            counts: StableBTreeMap::init(counts),
+        }
+    }
+
+    // Adding a posting that already exists is a no-op
+    pub(crate) fn insert(&mut self, key: &str, name: &str) {
+        let posting = Posting {
+            key: key.to_string(),
+            name: name.to_string(),
+        };
+        if self.postings.insert(posting, ()).is_none() {
+            self.counts.insert(key.to_string(), self.count(key) + 1);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &str, name: &str) {
+        let posting = Posting {
+            key: key.to_string(),
+            name: name.to_string(),
+        };
+        if self.postings.remove(&posting).is_some() {
+            match self.count(key) {
+                0 | 1 => self.counts.remove(&key.to_string()),
+                count => self.counts.insert(key.to_string(), count - 1),
+            };
+        }
+    }
+
+    // Number of files with `key`
+    pub(crate) fn count(&self, key: &str) -> u64 {
+        self.counts.get(&key.to_string()).unwrap_or(0)
+    }
+
+    // Keys starting with `prefix` and their counts, in key order
+    pub(crate) fn keys_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (String, u64)> + 'a {
+        self.counts
+            .range(prefix.to_string()..)
+            .take_while(move |(key, _)| key.starts_with(prefix))
+    }
+
+    // Every key and its count in key order, starting after `after`
+    pub(crate) fn keys_after(
+        &self,
+        after: Option<&String>,
+    ) -> impl Iterator<Item = (String, u64)> + '_ {
+        let start = match after {
+            Some(key) => Bound::Excluded(key.clone()),
+            None => Bound::Unbounded,
+        };
+        self.counts.range((start, Bound::Unbounded))
+    }
+
+    // Names of the files with `key`, in name order, starting after `after`
+    pub(crate) fn names(
+        &self,
+        key: &str,
+        after: Option<&String>,
+    ) -> impl Iterator<Item = String> + '_ {
+        let key = key.to_string();
+        let start = match after {
+            Some(name) => Bound::Excluded(Posting {
+                key: key.clone(),
+                name: name.clone(),
+            }),
+            None => Bound::Included(Posting {
+                key: key.clone(),
+                name: String::new(),
+            }),
+        };
+        self.postings
+            .keys_range((start, Bound::Unbounded))
+            .take_while(move |posting| posting.key == key)
+            .map(|posting| posting.name)
+    }
+}
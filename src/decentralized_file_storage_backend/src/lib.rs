@@ -1,7 +1,50 @@
-use candid::{CandidType, Deserialize};
-use ic_cdk_macros::{init, query, update};
-use std::collections::HashMap;
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk_macros::{init, post_upgrade, query, update};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::cell::RefCell;
+use std::time::Duration;
+
+mod attributes;
+mod expiry;
+mod facets;
+mod folders;
+mod fulltext;
+mod http;
+mod index;
+mod listing;
+mod migration;
+mod patch;
+mod retention;
+mod search;
+mod tags;
+mod trash;
+
+use expiry::validate_expiry;
+use folders::{normalize_entry_path, FolderMetadata};
+use patch::{validate_file_type, validate_tags};
+// Types used in endpoints defined in submodules, in scope for export_candid!
+use attributes::FileAttribute;
+use facets::{FacetCount, SearchFacets};
+use folders::FolderListing;
+use fulltext::{TermPosting, TermPositions, TextDocument, TextIndex, TextSearchHit};
+use listing::{FilePage, FilePosition, ListFilesRequest};
+use patch::{MetadataChange, MetadataPatch};
+use retention::{LegalHoldEvent, RetentionStatus, TagRetention};
+use index::{Posting, PostingIndex};
+use search::FileQuery;
+use tags::TagInfo;
+use trash::{EmptyTrashResult, TrashPage, TrashedFile, DEFAULT_TRASH_RETENTION};
+use http::{HttpRequest, HttpResponse, StreamingCallbackHttpResponse, StreamingCallbackToken};
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// SHA-256 digest, used both to address chunks and to verify whole files
+type Sha256Hash = [u8; 32];
 
 // Custom error type for better error handling
 #[derive(CandidType, Deserialize, Clone, Debug)]
@@ -12,6 +55,40 @@ pub enum StorageError {
     StorageLimit,
     InvalidFileType,
     SystemError,
+    UploadNotFound,
+    IncompleteUpload,
+    ChecksumMismatch,
+    Unauthorized,
+    VersionNotFound,
+    InvalidPath,
+    FolderNotFound,
+    RevisionConflict,
+    RetentionActive,
+}
+
+// Rights that can be granted on a file, each including the ones before it
+#[derive(CandidType, Clone, Copy, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+enum ShareRole {
+    Read,
+    Write,
+    Admin,
+}
+
+#[derive(CandidType, Clone, Deserialize)]
+struct ShareEntry {
+    principal: Principal,
+    role: ShareRole,
+}
+
+// One entry in a file's version history
+#[derive(CandidType, Clone, Deserialize)]
+struct FileVersion {
+    version: u64,
+    size: usize,
+    author: Principal,
+    created_at: u64,
+    sha256: Sha256Hash, // Hash of the whole version content
+    chunks: Vec<Sha256Hash>, // Hash of each chunk, in order
 }
 
 // Enhanced file metadata
@@ -23,8 +100,19 @@ struct FileMetadata {
     last_modified: u64,
     file_type: String,
     is_encrypted: bool,
-    version_history: Vec<String>, // For version control
+    version_history: Vec<FileVersion>, // For version control, oldest first
+    current_version: u64, // Version whose content is served as the live file
     tags: Vec<String>,
+    chunk_count: u32,
+    sha256: Sha256Hash, // Hash of the live content
+    owner: Principal,
+    shares: Vec<ShareEntry>,
+    revision: u64, // Bumped on every change to content or metadata
+    attributes: Vec<FileAttribute>, // Custom attributes, sorted by key
+    metadata_history: Vec<MetadataChange>, // Recent metadata patches, oldest first
+    expires_at: Option<u64>, // Deleted once this time has passed
+    retain_until: Option<u64>, // No deletion or overwrite before this time
+    legal_hold: bool, // No deletion or overwrite until cleared
 }
 
 #[derive(CandidType, Clone, Deserialize)]
@@ -34,28 +122,691 @@ struct File {
     metadata: FileMetadata,
 }
 
-#[derive(Default)]
-struct FileStorage {
-    files: HashMap<String, File>,
-    file_chunks: HashMap<String, Vec<Vec<u8>>>, // For storing file chunks
+// Identifies a chunk received for an upload session that has not been committed yet
+#[derive(CandidType, Clone, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct UploadChunkKey {
+    upload_id: u64,
+    index: u32,
+}
+
+// In-progress multi-call upload
+#[derive(CandidType, Clone, Deserialize)]
+struct UploadSession {
+    owner: Principal,
+    name: String,
+    file_type: String,
+    tags: Vec<String>,
+    total_size: usize,
+    chunk_count: u32,
+    received: Vec<bool>,
+    started_at: u64,
+    overwrite: bool,
+    expires_at: Option<u64>,
+    reserved: usize, // Bytes held back from capacity until the session ends
+}
+
+// Usage counters kept alongside the files
+#[derive(CandidType, Clone, Deserialize)]
+struct StorageStats {
     storage_usage: usize,
     max_storage_size: usize,
+    next_upload_id: u64,
+    next_trash_id: u64,
+    trash_retention: u64, // Seconds a deleted file stays restorable
+    reserved_bytes: usize, // Capacity reserved by open upload sessions
 }
 
+// Everything lives in stable memory so it survives canister upgrades.
+// File content is not stored with the metadata; each version lists the hashes of its chunks.
+struct FileStorage {
+    files: StableBTreeMap<String, FileMetadata, Memory>,
+    file_chunks: StableBTreeMap<Sha256Hash, Vec<u8>, Memory>, // Content-addressed chunk store
+    chunk_refs: StableBTreeMap<Sha256Hash, u64, Memory>, // Versions referencing each chunk
+    stats: StableCell<StorageStats, Memory>,
+    uploads: StableBTreeMap<u64, UploadSession, Memory>,
+    upload_chunks: StableBTreeMap<UploadChunkKey, Vec<u8>, Memory>,
+    folders: StableBTreeMap<String, FolderMetadata, Memory>,
+    // Sort orders for list_files; keep in step with `files` via put_file/take_file
+    size_index: StableBTreeMap<FilePosition, (), Memory>,
+    uploaded_index: StableBTreeMap<FilePosition, (), Memory>,
+    modified_index: StableBTreeMap<FilePosition, (), Memory>,
+    // Files with an expiry time, soonest first
+    expiry_index: StableBTreeMap<FilePosition, (), Memory>,
+    // Inverted indexes for search, maintained the same way
+    tag_index: PostingIndex,
+    type_index: PostingIndex,
+    owner_index: PostingIndex,
+    attribute_index: PostingIndex,
+    text_index: TextIndex,
+    // Tag hierarchy: the parent of each tag, and the children of each tag
+    tag_parents: StableBTreeMap<String, String, Memory>,
+    tag_children: PostingIndex,
+    trash: StableBTreeMap<u64, TrashedFile, Memory>, // Deleted files by trash id
+    tag_retention: StableBTreeMap<String, u64, Memory>, // Retention period per tag
+    legal_hold_log: StableBTreeMap<u64, LegalHoldEvent, Memory>, // Audit trail by id
+}
+
+// Stable types are stored in their Candid encoding. Types that gained fields after
+// they were first stored are decoded leniently instead; see migration.rs.
+macro_rules! impl_candid_storable {
+    ($($t:ty),*) => {
+        $(
+            impl Storable for $t {
+                fn to_bytes(&self) -> Cow<'_, [u8]> {
+                    Cow::Owned(Encode!(self).expect("failed to encode stable value"))
+                }
+
+                fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                    Decode!(bytes.as_ref(), Self).expect("failed to decode stable value")
+                }
+
+                const BOUND: Bound = Bound::Unbounded;
+            }
+        )*
+    };
+}
+
+impl_candid_storable!(
+    UploadChunkKey,
+    FolderMetadata,
+    FilePosition,
+    Posting,
+    TermPosting,
+    TermPositions,
+    TextDocument,
+    LegalHoldEvent
+);
+
 // Constants
 const MAX_FILE_SIZE: usize = 10 * 1024 * 1024; // 10MB
 const CHUNK_SIZE: usize = 1024 * 1024; // 1MB per chunk
 const MAX_STORAGE_SIZE: usize = 1024 * 1024 * 1024; // 1GB total storage
+const MAX_VERSIONS: usize = 100; // Versions per file; delete_version makes room
+const MAX_READ_SIZE: usize = 2 * CHUNK_SIZE; // Keeps read_range replies under the response limit
+const MAX_UPLOADS_PER_CALLER: usize = 4; // Open upload sessions per principal
+const MAX_UPLOADS: u64 = 1000; // Open upload sessions in total
+const UPLOAD_TTL: u64 = 24 * 60 * 60; // Seconds before an unfinished upload is dropped
+// How often the timer looks for stale upload sessions
+const UPLOAD_EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Stable memory layout. Never reuse or renumber an id once deployed.
+const FILES_MEMORY_ID: MemoryId = MemoryId::new(0);
+// Per-file chunks before the store became content-addressed; emptied by migration.rs
+const LEGACY_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(1);
+const STATS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const UPLOADS_MEMORY_ID: MemoryId = MemoryId::new(3);
+const UPLOAD_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(4);
+const CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const CHUNK_REFS_MEMORY_ID: MemoryId = MemoryId::new(6);
+const FOLDERS_MEMORY_ID: MemoryId = MemoryId::new(7);
+const SIZE_INDEX_MEMORY_ID: MemoryId = MemoryId::new(8);
+const UPLOADED_INDEX_MEMORY_ID: MemoryId = MemoryId::new(9);
+const MODIFIED_INDEX_MEMORY_ID: MemoryId = MemoryId::new(10);
+const TAG_POSTINGS_MEMORY_ID: MemoryId = MemoryId::new(11);
+const TAG_COUNTS_MEMORY_ID: MemoryId = MemoryId::new(12);
+const TYPE_POSTINGS_MEMORY_ID: MemoryId = MemoryId::new(13);
+const TYPE_COUNTS_MEMORY_ID: MemoryId = MemoryId::new(14);
+const OWNER_POSTINGS_MEMORY_ID: MemoryId = MemoryId::new(15);
+const OWNER_COUNTS_MEMORY_ID: MemoryId = MemoryId::new(16);
+const TEXT_POSTINGS_MEMORY_ID: MemoryId = MemoryId::new(17);
+const TEXT_DOCUMENTS_MEMORY_ID: MemoryId = MemoryId::new(18);
+const TEXT_FREQUENCIES_MEMORY_ID: MemoryId = MemoryId::new(19);
+const TAG_PARENTS_MEMORY_ID: MemoryId = MemoryId::new(20);
+const TAG_CHILDREN_MEMORY_ID: MemoryId = MemoryId::new(21);
+const TAG_CHILD_COUNTS_MEMORY_ID: MemoryId = MemoryId::new(22);
+const ATTRIBUTE_POSTINGS_MEMORY_ID: MemoryId = MemoryId::new(23);
+const ATTRIBUTE_COUNTS_MEMORY_ID: MemoryId = MemoryId::new(24);
+const TRASH_MEMORY_ID: MemoryId = MemoryId::new(25);
+const EXPIRY_INDEX_MEMORY_ID: MemoryId = MemoryId::new(26);
+const TAG_RETENTION_MEMORY_ID: MemoryId = MemoryId::new(27);
+const LEGAL_HOLD_LOG_MEMORY_ID: MemoryId = MemoryId::new(28);
+const SCHEMA_MEMORY_ID: MemoryId = MemoryId::new(29);
+const TEXT_HASHES_MEMORY_ID: MemoryId = MemoryId::new(30);
 
 thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
     static STATE: RefCell<FileStorage> = RefCell::new(FileStorage {
-        files: HashMap::new(),
-        file_chunks: HashMap::new(),
-        storage_usage: 0,
-        max_storage_size: MAX_STORAGE_SIZE,
+        files: StableBTreeMap::init(get_memory(FILES_MEMORY_ID)),
+        file_chunks: StableBTreeMap::init(get_memory(CHUNKS_MEMORY_ID)),
+        chunk_refs: StableBTreeMap::init(get_memory(CHUNK_REFS_MEMORY_ID)),
+        stats: StableCell::init(
+            get_memory(STATS_MEMORY_ID),
+            StorageStats {
+                storage_usage: 0,
+                max_storage_size: MAX_STORAGE_SIZE,
+                next_upload_id: 0,
+                next_trash_id: 0,
+                trash_retention: DEFAULT_TRASH_RETENTION,
+                reserved_bytes: 0,
+            },
+        )
+        .expect("failed to initialize storage stats"),
+        uploads: StableBTreeMap::init(get_memory(UPLOADS_MEMORY_ID)),
+        upload_chunks: StableBTreeMap::init(get_memory(UPLOAD_CHUNKS_MEMORY_ID)),
+        folders: StableBTreeMap::init(get_memory(FOLDERS_MEMORY_ID)),
+        size_index: StableBTreeMap::init(get_memory(SIZE_INDEX_MEMORY_ID)),
+        uploaded_index: StableBTreeMap::init(get_memory(UPLOADED_INDEX_MEMORY_ID)),
+        modified_index: StableBTreeMap::init(get_memory(MODIFIED_INDEX_MEMORY_ID)),
+        expiry_index: StableBTreeMap::init(get_memory(EXPIRY_INDEX_MEMORY_ID)),
+        tag_index: PostingIndex::init(
+            get_memory(TAG_POSTINGS_MEMORY_ID),
+            get_memory(TAG_COUNTS_MEMORY_ID),
+        ),
+        type_index: PostingIndex::init(
+            get_memory(TYPE_POSTINGS_MEMORY_ID),
+            get_memory(TYPE_COUNTS_MEMORY_ID),
+        ),
+        owner_index: PostingIndex::init(
+            get_memory(OWNER_POSTINGS_MEMORY_ID),
+            get_memory(OWNER_COUNTS_MEMORY_ID),
+        ),
+        attribute_index: PostingIndex::init(
+            get_memory(ATTRIBUTE_POSTINGS_MEMORY_ID),
+            get_memory(ATTRIBUTE_COUNTS_MEMORY_ID),
+        ),
+        text_index: TextIndex::init(
+            get_memory(TEXT_POSTINGS_MEMORY_ID),
+            get_memory(TEXT_DOCUMENTS_MEMORY_ID),
+            get_memory(TEXT_FREQUENCIES_MEMORY_ID),
+            get_memory(TEXT_HASHES_MEMORY_ID),
+        ),
+        tag_parents: StableBTreeMap::init(get_memory(TAG_PARENTS_MEMORY_ID)),
+        tag_children: PostingIndex::init(
+            get_memory(TAG_CHILDREN_MEMORY_ID),
+            get_memory(TAG_CHILD_COUNTS_MEMORY_ID),
+        ),
+        trash: StableBTreeMap::init(get_memory(TRASH_MEMORY_ID)),
+        tag_retention: StableBTreeMap::init(get_memory(TAG_RETENTION_MEMORY_ID)),
+        legal_hold_log: StableBTreeMap::init(get_memory(LEGAL_HOLD_LOG_MEMORY_ID)),
+    });
+}
+
+fn get_memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(id))
+}
+
+impl FileStorage {
+    fn storage_usage(&self) -> usize {
+        self.stats.get().storage_usage
+    }
+
+    fn max_storage_size(&self) -> usize {
+        self.stats.get().max_storage_size
+    }
+
+    fn set_storage_usage(&mut self, storage_usage: usize) {
+        let mut stats = self.stats.get().clone();
+        stats.storage_usage = storage_usage;
+        self.stats.set(stats).expect("failed to persist storage stats");
+    }
+
+    fn reserved_bytes(&self) -> usize {
+        self.stats.get().reserved_bytes
+    }
+
+    fn reserve(&mut self, bytes: usize) {
+        let mut stats = self.stats.get().clone();
+        stats.reserved_bytes += bytes;
+        self.stats.set(stats).expect("failed to persist storage stats");
+    }
+
+    fn release_reservation(&mut self, bytes: usize) {
+        let mut stats = self.stats.get().clone();
+        stats.reserved_bytes = stats.reserved_bytes.saturating_sub(bytes);
+        self.stats.set(stats).expect("failed to persist storage stats");
+    }
+
+    // Whether `bytes` more fit, counting capacity reserved by upload sessions
+    fn has_capacity(&self, bytes: usize) -> bool {
+        self.storage_usage() + self.reserved_bytes() + bytes <= self.max_storage_size()
+    }
+
+    fn next_upload_id(&mut self) -> u64 {
+        let mut stats = self.stats.get().clone();
+        let upload_id = stats.next_upload_id;
+        stats.next_upload_id += 1;
+        self.stats.set(stats).expect("failed to persist storage stats");
+        upload_id
+    }
+
+    // Stores a file's metadata under its name, updating the secondary indexes
+    fn put_file(&mut self, metadata: FileMetadata) {
+        if let Some(previous) = self.files.get(&metadata.name) {
+            self.unindex_sort_keys(&previous);
+            self.unindex_postings(&previous);
+            self.unindex_expiry(&previous);
+        }
+        self.index_sort_keys(&metadata);
+        self.index_postings(&metadata);
+        self.index_expiry(&metadata);
+        self.index_text(&metadata);
+        self.files.insert(metadata.name.clone(), metadata);
+    }
+
+    // Removes a file's metadata and its index entries; chunks are left alone
+    fn take_file(&mut self, name: &String) -> Option<FileMetadata> {
+        let metadata = self.files.remove(name)?;
+        self.unindex_sort_keys(&metadata);
+        self.unindex_postings(&metadata);
+        self.unindex_expiry(&metadata);
+        self.text_index.remove_document(name);
+        Some(metadata)
+    }
+
+    // Store a complete file under a normalized path, checking size and capacity first.
+    // If the file exists, the content becomes its next version and the previous
+    // content stays in the version history.
+    #[allow(clippy::too_many_arguments)]
+    fn insert_file(
+        &mut self,
+        caller: Principal,
+        name: String,
+        content: Vec<u8>,
+        file_type: String,
+        tags: Vec<String>,
+        expires_at: Option<u64>,
+        expected_sha256: Option<Vec<u8>>,
+    ) -> Result<(), StorageError> {
+        // Overwriting a file requires the same rights as modifying it
+        let existing = self.files.get(&name);
+        if let Some(existing) = &existing {
+            authorize_access(caller, existing, ShareRole::Write)?;
+            check_version_room(existing)?;
+        }
+
+        // Validate file size
+        if content.len() > MAX_FILE_SIZE {
+            return Err(StorageError::StorageLimit);
+        }
+        validate_expiry(expires_at)?;
+
+        // New files need a free path; parent folders are created once nothing can fail
+        if existing.is_none() {
+            self.check_path(&name, caller)?;
+        }
+
+        // Verify integrity against the hash the client computed, if any
+        let sha256: Sha256Hash = Sha256::digest(&content).into();
+        if let Some(expected) = expected_sha256 {
+            if sha256.as_slice() != expected.as_slice() {
+                return Err(StorageError::ChecksumMismatch);
+            }
+        }
+
+        // Split file into chunks for better management
+        let chunks = self.store_content(&content)?;
+        if let Some(mut existing) = existing {
+            existing.file_type = file_type;
+            existing.tags = tags;
+            existing.expires_at = expires_at;
+            self.push_version(caller, existing, chunks, content.len(), sha256);
+            return Ok(());
+        }
+        self.create_ancestors(&name, caller);
+
+        // Create file metadata
+        let metadata = FileMetadata {
+            name: name.clone(),
+            size: content.len(),
+            upload_timestamp: get_current_timestamp(),
+            last_modified: get_current_timestamp(),
+            file_type,
+            is_encrypted: false,
+            version_history: vec![FileVersion {
+                version: 1,
+                size: content.len(),
+                author: caller,
+                created_at: get_current_timestamp(),
+                sha256,
+                chunks,
+            }],
+            current_version: 1,
+            tags,
+            chunk_count: chunk_count_for(content.len()),
+            sha256,
+            owner: caller,
+            shares: Vec::new(),
+            revision: 1,
+            attributes: Vec::new(),
+            metadata_history: Vec::new(),
+            expires_at,
+            retain_until: None,
+            legal_hold: false,
+        };
+
+        http::certify_file(&name, &sha256);
+        self.put_file(metadata);
+
+        Ok(())
+    }
+
+    // Splits content into CHUNK_SIZE pieces and takes a reference on each, storing
+    // only chunks not already held. Fails without storing anything if the new bytes
+    // would exceed capacity.
+    fn store_content(&mut self, content: &[u8]) -> Result<Vec<Sha256Hash>, StorageError> {
+        let chunks = split_chunks(content);
+
+        // Check storage capacity against the bytes that are actually new
+        let mut seen = HashSet::new();
+        let new_bytes: usize = chunks
+            .iter()
+            .filter(|(hash, _)| seen.insert(*hash) && !self.chunk_refs.contains_key(hash))
+            .map(|(_, chunk)| chunk.len())
+            .sum();
+        if !self.has_capacity(new_bytes) {
+            return Err(StorageError::StorageLimit);
+        }
+
+        Ok(self.put_chunks(&chunks))
+    }
+
+    // Takes a reference on each chunk, storing the ones not already held and counting
+    // their bytes towards storage usage. Does not check capacity.
+    fn put_chunks(&mut self, chunks: &[(Sha256Hash, &[u8])]) -> Vec<Sha256Hash> {
+        let mut new_bytes = 0;
+        for (hash, chunk) in chunks {
+            let refs = self.chunk_refs.get(hash).unwrap_or(0);
+            if refs == 0 {
+                self.file_chunks.insert(*hash, chunk.to_vec());
+                new_bytes += chunk.len();
+            }
+            self.chunk_refs.insert(*hash, refs + 1);
+        }
+        self.set_storage_usage(self.storage_usage() + new_bytes);
+        chunks.iter().map(|(hash, _)| *hash).collect()
+    }
+
+    // Moves a file to a new normalized path, keeping its metadata and versions. The
+    // caller stores the updated metadata afterwards with put_file.
+    fn move_metadata(
+        &mut self,
+        caller: Principal,
+        metadata: &mut FileMetadata,
+        destination: String,
+    ) -> Result<(), StorageError> {
+        self.free_expired_name(&destination)?;
+        if self.files.contains_key(&destination) {
+            return Err(StorageError::FileAlreadyExists);
+        }
+        self.prepare_path(&destination, caller)?;
+
+        let source = std::mem::replace(&mut metadata.name, destination);
+        self.take_file(&source);
+        http::uncertify_file(&source);
+        http::certify_file(&metadata.name, &metadata.sha256);
+        Ok(())
+    }
+
+    // Takes another reference on chunks that are already stored
+    fn retain_chunks(&mut self, hashes: &[Sha256Hash]) {
+        for hash in hashes {
+            let refs = self.chunk_refs.get(hash).unwrap_or(0);
+            self.chunk_refs.insert(*hash, refs + 1);
+        }
+    }
+
+    // Drops the references every version of a file holds on its chunks
+    fn release_versions(&mut self, metadata: &FileMetadata) {
+        for version in &metadata.version_history {
+            self.release_chunks(&version.chunks);
+        }
+    }
+
+    // Drops a reference on each chunk, freeing chunks nothing refers to anymore
+    fn release_chunks(&mut self, hashes: &[Sha256Hash]) {
+        let mut freed = 0;
+        for hash in hashes {
+            match self.chunk_refs.get(hash) {
+                Some(refs) if refs > 1 => {
+                    self.chunk_refs.insert(*hash, refs - 1);
+                }
+                Some(_) => {
+                    self.chunk_refs.remove(hash);
+                    if let Some(chunk) = self.file_chunks.remove(hash) {
+                        freed += chunk.len();
+                    }
+                }
+                None => {}
+            }
+        }
+        self.set_storage_usage(self.storage_usage() - freed);
+    }
+
+    fn read_chunk(&self, hash: &Sha256Hash) -> Option<Vec<u8>> {
+        self.file_chunks.get(hash)
+    }
+
+    // Reassembles the full content of one version of a file
+    fn read_version_content(&self, version: &FileVersion) -> Result<Vec<u8>, StorageError> {
+        let mut content = Vec::with_capacity(version.size);
+        for hash in &version.chunks {
+            let chunk = self.read_chunk(hash).ok_or(StorageError::SystemError)?;
+            content.extend_from_slice(&chunk);
+        }
+        Ok(content)
+    }
+
+    // Reads bytes `start..end` of one version, loading only the chunks they lie in
+    fn read_version_range(
+        &self,
+        version: &FileVersion,
+        start: usize,
+        end: usize,
+    ) -> Result<Vec<u8>, StorageError> {
+        let mut data = Vec::with_capacity(end.saturating_sub(start));
+        let mut position = start;
+        while position < end {
+            let index = (position / CHUNK_SIZE) as u32;
+            let chunk = version
+                .chunks
+                .get(index as usize)
+                .and_then(|hash| self.read_chunk(hash))
+                .ok_or(StorageError::SystemError)?;
+            let chunk_start = index as usize * CHUNK_SIZE;
+            let from = position - chunk_start;
+            let to = (end - chunk_start).min(chunk.len());
+            if to <= from {
+                return Err(StorageError::SystemError);
+            }
+            data.extend_from_slice(&chunk[from..to]);
+            position = chunk_start + to;
+        }
+        Ok(data)
+    }
+
+    // Appends already-referenced chunks as the newest version of a file and makes
+    // it the live content
+    fn push_version(
+        &mut self,
+        caller: Principal,
+        mut metadata: FileMetadata,
+        chunks: Vec<Sha256Hash>,
+        size: usize,
+        sha256: Sha256Hash,
+    ) -> u64 {
+        let now = get_current_timestamp();
+        let version = metadata.current_version + 1;
+
+        metadata.version_history.push(FileVersion {
+            version,
+            size,
+            author: caller,
+            created_at: now,
+            sha256,
+            chunks,
+        });
+        metadata.current_version = version;
+        metadata.size = size;
+        metadata.chunk_count = chunk_count_for(size);
+        metadata.sha256 = sha256;
+        touch(&mut metadata);
+
+        http::certify_file(&metadata.name, &sha256);
+        self.put_file(metadata);
+        version
+    }
+
+    // Checks that a whole-file upload may replace whatever is stored at `name`. An
+    // expired file is deleted first and counts as absent. An expected revision also
+    // requires the file to exist.
+    fn check_overwrite(
+        &mut self,
+        name: &String,
+        overwrite: bool,
+        expected_revision: Option<u64>,
+    ) -> Result<(), StorageError> {
+        self.free_expired_name(name)?;
+        match self.files.get(name) {
+            Some(_) if !overwrite => Err(StorageError::FileAlreadyExists),
+            Some(existing) => {
+                check_revision(&existing, expected_revision)?;
+                self.check_retention(&existing)
+            }
+            None if expected_revision.is_some() => Err(StorageError::RevisionConflict),
+            None => Ok(()),
+        }
+    }
+
+    // Drops an upload session and its chunks, releasing the capacity it reserved
+    fn remove_upload(&mut self, upload_id: u64) -> Option<UploadSession> {
+        let session = self.uploads.remove(&upload_id)?;
+        for index in 0..session.chunk_count {
+            self.upload_chunks.remove(&UploadChunkKey { upload_id, index });
+        }
+        self.release_reservation(session.reserved);
+        Some(session)
+    }
+
+    // Drops sessions started more than UPLOAD_TTL ago. Open sessions are capped at
+    // MAX_UPLOADS, so one pass stays within the instruction limit.
+    fn expire_uploads(&mut self) -> usize {
+        let cutoff = get_current_timestamp().saturating_sub(UPLOAD_TTL);
+        let stale: Vec<u64> = self
+            .uploads
+            .iter()
+            .filter(|(_, session)| session.started_at <= cutoff)
+            .map(|(upload_id, _)| upload_id)
+            .collect();
+        for upload_id in &stale {
+            self.remove_upload(*upload_id);
+        }
+        stale.len()
+    }
+}
+
+// Timers do not survive upgrades, so this runs on init and post_upgrade
+fn start_upload_expiry_timer() {
+    ic_cdk_timers::set_timer_interval(UPLOAD_EXPIRY_INTERVAL, || {
+        STATE.with(|state| state.borrow_mut().expire_uploads());
     });
 }
 
+// Content split into CHUNK_SIZE pieces, each with its hash
+fn split_chunks(content: &[u8]) -> Vec<(Sha256Hash, &[u8])> {
+    content
+        .chunks(CHUNK_SIZE)
+        .map(|chunk| (Sha256::digest(chunk).into(), chunk))
+        .collect()
+}
+
+// Number of CHUNK_SIZE chunks needed to hold `size` bytes
+fn chunk_count_for(size: usize) -> u32 {
+    size.div_ceil(CHUNK_SIZE) as u32
+}
+
+// Expected length of chunk `index` for a file of `total_size` bytes
+fn expected_chunk_len(total_size: usize, index: u32) -> usize {
+    let offset = index as usize * CHUNK_SIZE;
+    (total_size - offset).min(CHUNK_SIZE)
+}
+
+fn find_version(metadata: &FileMetadata, version: u64) -> Result<&FileVersion, StorageError> {
+    metadata
+        .version_history
+        .iter()
+        .find(|entry| entry.version == version)
+        .ok_or(StorageError::VersionNotFound)
+}
+
+// The version currently served as the file's content
+fn live_version(metadata: &FileMetadata) -> Result<&FileVersion, StorageError> {
+    find_version(metadata, metadata.current_version).map_err(|_| StorageError::SystemError)
+}
+
+// Records a change to a file; clients holding the previous revision will now conflict
+fn touch(metadata: &mut FileMetadata) {
+    metadata.last_modified = get_current_timestamp();
+    metadata.revision += 1;
+}
+
+// Optimistic concurrency check: fails if the file changed since the caller read
+// `expected_revision`. No expectation means last write wins.
+fn check_revision(
+    metadata: &FileMetadata,
+    expected_revision: Option<u64>,
+) -> Result<(), StorageError> {
+    match expected_revision {
+        Some(expected) if expected != metadata.revision => Err(StorageError::RevisionConflict),
+        _ => Ok(()),
+    }
+}
+
+// Versions share chunks, so a new version can cost no storage; the count is what
+// keeps a file's metadata from growing without bound
+fn check_version_room(metadata: &FileMetadata) -> Result<(), StorageError> {
+    if metadata.version_history.len() >= MAX_VERSIONS {
+        return Err(StorageError::StorageLimit);
+    }
+    Ok(())
+}
+
+// The caller of the current message; anonymous callers cannot store or change files
+fn authenticated_caller() -> Result<Principal, StorageError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(StorageError::Unauthorized);
+    }
+    Ok(caller)
+}
+
+// Highest role the caller holds on a file. Owners and controllers hold every right.
+fn access_role(caller: Principal, metadata: &FileMetadata) -> Option<ShareRole> {
+    if caller == metadata.owner || ic_cdk::api::is_controller(&caller) {
+        return Some(ShareRole::Admin);
+    }
+    metadata
+        .shares
+        .iter()
+        .find(|share| share.principal == caller)
+        .map(|share| share.role)
+}
+
+fn can_access(caller: Principal, metadata: &FileMetadata, required: ShareRole) -> bool {
+    access_role(caller, metadata).is_some_and(|role| role >= required)
+}
+
+// Settings that affect every file are reserved to controllers
+fn authorize_controller() -> Result<(), StorageError> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(StorageError::Unauthorized);
+    }
+    Ok(())
+}
+
+fn authorize_access(
+    caller: Principal,
+    metadata: &FileMetadata,
+    required: ShareRole,
+) -> Result<(), StorageError> {
+    if can_access(caller, metadata, required) {
+        Ok(())
+    } else {
+        Err(StorageError::Unauthorized)
+    }
+}
+
 // Helper function to get current time
 fn get_current_timestamp() -> u64 {
     ic_cdk::api::time() / 1_000_000_000 // Convert nanoseconds to seconds
@@ -64,76 +815,334 @@ fn get_current_timestamp() -> u64 {
 // Initialize the canister
 #[init]
 fn init() {
-    // Initialization handled by thread_local
+    // Storage is initialized by thread_local; only the schema version and the timers
+    // need setting up
+    migration::init_schema();
+    trash::start_purge_timer();
+    expiry::start_expiry_timer();
+    start_upload_expiry_timer();
+}
+
+// Stable structures survive the upgrade on their own, though data written by an
+// earlier version may need migrating; heap-side certification and timers need rebuilding
+#[post_upgrade]
+fn post_upgrade() {
+    migration::migrate();
+    STATE.with(|state| http::certify_all(&state.borrow()));
+    trash::start_purge_timer();
+    expiry::start_expiry_timer();
+    start_upload_expiry_timer();
 }
 
 // CRUD Operations with error handling
+
+// Candid arguments are positional, so options cannot be grouped without breaking
+// clients. Every argument after `tags` is optional, so older callers that pass four
+// or five arguments keep working.
 #[update]
-fn upload_file(name: String, content: Vec<u8>, file_type: String, tags: Vec<String>) -> Result<bool, StorageError> {
+#[allow(clippy::too_many_arguments)]
+fn upload_file(
+    name: String,
+    content: Vec<u8>,
+    file_type: String,
+    tags: Vec<String>,
+    expected_sha256: Option<Vec<u8>>,
+    overwrite: Option<bool>,
+    expected_revision: Option<u64>,
+    expires_at: Option<u64>,
+) -> Result<bool, StorageError> {
+    let caller = authenticated_caller()?;
+    let overwrite = overwrite.unwrap_or(false);
+    let name = normalize_entry_path(&name)?;
+    validate_file_type(&file_type)?;
+    let tags = validate_tags(tags)?;
     STATE.with(|state| {
         let mut storage = state.borrow_mut();
-        
-        // Validate file size
-        if content.len() > MAX_FILE_SIZE {
+        storage.check_overwrite(&name, overwrite, expected_revision)?;
+        storage.insert_file(
+            caller,
+            name,
+            content,
+            file_type,
+            tags,
+            expires_at,
+            expected_sha256,
+        )?;
+        Ok(true)
+    })
+}
+
+// Multi-call upload protocol for files larger than a single ingress message.
+// Chunks may arrive in any order and be re-sent; nothing is visible until commit.
+// The session reserves `total_size` bytes of capacity until it is committed, aborted,
+// or dropped UPLOAD_TTL after it began.
+#[update]
+fn begin_upload(
+    name: String,
+    file_type: String,
+    tags: Vec<String>,
+    total_size: usize,
+    overwrite: Option<bool>,
+    expires_at: Option<u64>,
+) -> Result<u64, StorageError> {
+    let caller = authenticated_caller()?;
+    let overwrite = overwrite.unwrap_or(false);
+    let name = normalize_entry_path(&name)?;
+    validate_file_type(&file_type)?;
+    let tags = validate_tags(tags)?;
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+
+        // Fail early rather than at commit if the file may not be overwritten
+        storage.free_expired_name(&name)?;
+        if let Some(existing) = storage.files.get(&name) {
+            if !overwrite {
+                return Err(StorageError::FileAlreadyExists);
+            }
+            authorize_access(caller, &existing, ShareRole::Write)?;
+            storage.check_retention(&existing)?;
+            check_version_room(&existing)?;
+        } else {
+            storage.check_path(&name, caller)?;
+        }
+        if total_size > MAX_FILE_SIZE {
             return Err(StorageError::StorageLimit);
         }
-        
-        // Check storage capacity
-        if storage.storage_usage + content.len() > storage.max_storage_size {
+        validate_expiry(expires_at)?;
+        if !storage.has_capacity(total_size) {
+            return Err(StorageError::StorageLimit);
+        }
+        let open = storage
+            .uploads
+            .iter()
+            .filter(|(_, session)| session.owner == caller)
+            .count();
+        if open >= MAX_UPLOADS_PER_CALLER || storage.uploads.len() >= MAX_UPLOADS {
             return Err(StorageError::StorageLimit);
         }
 
-        // Create file metadata
-        let metadata = FileMetadata {
-            name: name.clone(),
-            size: content.len(),
-            upload_timestamp: get_current_timestamp(),
-            last_modified: get_current_timestamp(),
+        let chunk_count = chunk_count_for(total_size);
+        let session = UploadSession {
+            owner: caller,
+            name,
             file_type,
-            is_encrypted: false,
-            version_history: Vec::new(),
             tags,
+            total_size,
+            chunk_count,
+            received: vec![false; chunk_count as usize],
+            started_at: get_current_timestamp(),
+            overwrite,
+            expires_at,
+            reserved: total_size,
         };
 
-        // Split file into chunks for better management
-        let chunks: Vec<Vec<u8>> = content
-            .chunks(CHUNK_SIZE)
-            .map(|chunk| chunk.to_vec())
-            .collect();
+        let upload_id = storage.next_upload_id();
+        storage.reserve(total_size);
+        storage.uploads.insert(upload_id, session);
+        Ok(upload_id)
+    })
+}
 
-        let file = File {
-            name: name.clone(),
-            content: content.clone(),
-            metadata,
-        };
+#[update]
+fn upload_chunk(upload_id: u64, index: u32, data: Vec<u8>) -> Result<bool, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
 
-        storage.files.insert(name.clone(), file);
-        storage.file_chunks.insert(name, chunks);
-        storage.storage_usage += content.len();
+        let mut session = storage
+            .uploads
+            .get(&upload_id)
+            .ok_or(StorageError::UploadNotFound)?;
+        if session.owner != caller {
+            return Err(StorageError::Unauthorized);
+        }
 
+        if index >= session.chunk_count
+            || data.len() != expected_chunk_len(session.total_size, index)
+        {
+            return Err(StorageError::InvalidOperation);
+        }
+
+        storage
+            .upload_chunks
+            .insert(UploadChunkKey { upload_id, index }, data);
+        session.received[index as usize] = true;
+        storage.uploads.insert(upload_id, session);
+        Ok(true)
+    })
+}
+
+#[query]
+fn get_missing_chunks(upload_id: u64) -> Result<Vec<u32>, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let storage = state.borrow();
+        let session = storage
+            .uploads
+            .get(&upload_id)
+            .ok_or(StorageError::UploadNotFound)?;
+        if session.owner != caller {
+            return Err(StorageError::Unauthorized);
+        }
+
+        Ok((0..session.chunk_count)
+            .filter(|index| !session.received[*index as usize])
+            .collect())
+    })
+}
+
+#[update]
+fn commit_upload(
+    upload_id: u64,
+    expected_size: usize,
+    expected_sha256: Option<Vec<u8>>,
+    expected_revision: Option<u64>,
+) -> Result<bool, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+
+        let session = storage
+            .uploads
+            .get(&upload_id)
+            .ok_or(StorageError::UploadNotFound)?;
+        if session.owner != caller {
+            return Err(StorageError::Unauthorized);
+        }
+
+        if session.received.iter().any(|received| !received) {
+            return Err(StorageError::IncompleteUpload);
+        }
+        if expected_size != session.total_size {
+            return Err(StorageError::InvalidOperation);
+        }
+        // The file may have been created or changed by someone else since the session began
+        storage.check_overwrite(&session.name, session.overwrite, expected_revision)?;
+
+        let mut content = Vec::with_capacity(session.total_size);
+        for index in 0..session.chunk_count {
+            let chunk = storage
+                .upload_chunks
+                .get(&UploadChunkKey { upload_id, index })
+                .ok_or(StorageError::SystemError)?;
+            content.extend_from_slice(&chunk);
+        }
+
+        // The file's bytes take the place of the session's reservation while it is
+        // stored. The reservation comes back either way: remove_upload releases it on
+        // success, and a failed commit leaves the session open.
+        storage.release_reservation(session.reserved);
+        let inserted = storage.insert_file(
+            caller,
+            session.name,
+            content,
+            session.file_type,
+            session.tags,
+            session.expires_at,
+            expected_sha256,
+        );
+        storage.reserve(session.reserved);
+        inserted?;
+        storage.remove_upload(upload_id);
+        Ok(true)
+    })
+}
+
+#[update]
+fn abort_upload(upload_id: u64) -> Result<bool, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+
+        let session = storage
+            .uploads
+            .get(&upload_id)
+            .ok_or(StorageError::UploadNotFound)?;
+        if session.owner != caller && !ic_cdk::api::is_controller(&caller) {
+            return Err(StorageError::Unauthorized);
+        }
+
+        storage.remove_upload(upload_id);
         Ok(true)
     })
 }
 
 #[query]
 fn download_file(name: String) -> Result<File, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let storage = state.borrow();
+        let metadata = storage.unexpired_file(&name)?;
+        authorize_access(caller, &metadata, ShareRole::Read)?;
+
+        let content = storage.read_version_content(live_version(&metadata)?)?;
+        Ok(File {
+            name,
+            content,
+            metadata,
+        })
+    })
+}
+
+// Metadata without content, for clients that stream the file chunk by chunk
+#[query]
+fn get_file_metadata(name: String) -> Result<FileMetadata, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let storage = state.borrow();
+        let metadata = storage.unexpired_file(&name)?;
+        authorize_access(caller, &metadata, ShareRole::Read)?;
+        Ok(metadata)
+    })
+}
+
+#[query]
+fn get_chunk(name: String, index: u32) -> Result<Vec<u8>, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let storage = state.borrow();
+        let metadata = storage.unexpired_file(&name)?;
+        authorize_access(caller, &metadata, ShareRole::Read)?;
+
+        let hash = live_version(&metadata)?
+            .chunks
+            .get(index as usize)
+            .ok_or(StorageError::InvalidOperation)?;
+        storage.read_chunk(hash).ok_or(StorageError::SystemError)
+    })
+}
+
+// Reads up to `len` bytes starting at `offset`. The result is shorter than `len`
+// when the range runs past the end of the file or exceeds MAX_READ_SIZE.
+#[query]
+fn read_range(name: String, offset: usize, len: usize) -> Result<Vec<u8>, StorageError> {
+    let caller = ic_cdk::caller();
     STATE.with(|state| {
         let storage = state.borrow();
-        storage.files
-            .get(&name)
-            .cloned()
-            .ok_or(StorageError::FileNotFound)
+        let metadata = storage.unexpired_file(&name)?;
+        authorize_access(caller, &metadata, ShareRole::Read)?;
+        let live = live_version(&metadata)?;
+
+        if offset > metadata.size {
+            return Err(StorageError::InvalidOperation);
+        }
+        let end = offset + len.min(MAX_READ_SIZE).min(metadata.size - offset);
+        storage.read_version_range(live, offset, end)
     })
 }
 
+// Moves a file to the trash, from where restore_file can bring it back until it is purged
 #[update]
-fn delete_file(name: String) -> Result<bool, StorageError> {
+fn delete_file(name: String, expected_revision: Option<u64>) -> Result<bool, StorageError> {
+    let caller = ic_cdk::caller();
     STATE.with(|state| {
         let mut storage = state.borrow_mut();
         
-        if let Some(file) = storage.files.remove(&name) {
-            storage.storage_usage -= file.content.len();
-            storage.file_chunks.remove(&name);
+        if let Some(metadata) = storage.files.get(&name) {
+            authorize_access(caller, &metadata, ShareRole::Admin)?;
+            check_revision(&metadata, expected_revision)?;
+            storage.check_retention(&metadata)?;
+            storage.trash_file(caller, &name);
             Ok(true)
         } else {
             Err(StorageError::FileNotFound)
@@ -141,62 +1150,245 @@ fn delete_file(name: String) -> Result<bool, StorageError> {
     })
 }
 
+// Moves a file to another path, possibly in another folder. Metadata, version
+// history and shares move with it.
+#[update]
+fn move_file(source: String, destination: String) -> Result<bool, StorageError> {
+    let caller = ic_cdk::caller();
+    let patch = MetadataPatch {
+        name: Some(destination),
+        ..Default::default()
+    };
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+        storage.patch_metadata(caller, &source, patch, None)?;
+        Ok(true)
+    })
+}
+
+// Renames a file within its folder; `new_name` is a single path segment
+#[update]
+fn rename_file(name: String, new_name: String) -> Result<bool, StorageError> {
+    let caller = ic_cdk::caller();
+    if new_name.contains('/') {
+        return Err(StorageError::InvalidPath);
+    }
+    let destination = match name.rsplit_once('/') {
+        Some((folder, _)) => normalize_entry_path(&format!("{}/{}", folder, new_name))?,
+        None => normalize_entry_path(&new_name)?,
+    };
+    let patch = MetadataPatch {
+        name: Some(destination),
+        ..Default::default()
+    };
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+        storage.patch_metadata(caller, &name, patch, None)?;
+        Ok(true)
+    })
+}
+
+// Copies a file, including its version history, to a new path owned by the caller.
+// The copy shares chunks with the original, so it uses no extra storage.
+#[update]
+fn copy_file(source: String, destination: String) -> Result<bool, StorageError> {
+    let caller = authenticated_caller()?;
+    let destination = normalize_entry_path(&destination)?;
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+
+        let mut metadata = storage.files.get(&source).ok_or(StorageError::FileNotFound)?;
+        authorize_access(caller, &metadata, ShareRole::Read)?;
+        storage.free_expired_name(&destination)?;
+        if storage.files.contains_key(&destination) {
+            return Err(StorageError::FileAlreadyExists);
+        }
+        storage.prepare_path(&destination, caller)?;
+
+        for version in &metadata.version_history {
+            storage.retain_chunks(&version.chunks);
+        }
+        metadata.name = destination.clone();
+        metadata.owner = caller;
+        metadata.shares = Vec::new();
+        metadata.last_modified = get_current_timestamp();
+        metadata.revision = 1;
+        metadata.metadata_history = Vec::new();
+        metadata.retain_until = None;
+        metadata.legal_hold = false;
+
+        http::certify_file(&destination, &metadata.sha256);
+        storage.put_file(metadata);
+        Ok(true)
+    })
+}
+
 #[update]
 fn update_file_metadata(
     name: String,
     new_tags: Option<Vec<String>>,
+    expected_revision: Option<u64>,
 ) -> Result<bool, StorageError> {
+    let caller = ic_cdk::caller();
+    let patch = MetadataPatch {
+        tags: new_tags,
+        ..Default::default()
+    };
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+        storage.patch_metadata(caller, &name, patch, expected_revision)?;
+        Ok(true)
+    })
+}
+
+// Stores `content` as a new version of an existing file and makes it the live content.
+// Returns the new version number.
+#[update]
+fn create_file_version(
+    name: String,
+    content: Vec<u8>,
+    expected_revision: Option<u64>,
+) -> Result<u64, StorageError> {
+    let caller = ic_cdk::caller();
     STATE.with(|state| {
         let mut storage = state.borrow_mut();
         
-        if let Some(file) = storage.files.get_mut(&name) {
-            if let Some(tags) = new_tags {
-                file.metadata.tags = tags;
+        if let Some(metadata) = storage.files.get(&name) {
+            authorize_access(caller, &metadata, ShareRole::Write)?;
+            check_revision(&metadata, expected_revision)?;
+            storage.check_retention(&metadata)?;
+            check_version_room(&metadata)?;
+            if content.len() > MAX_FILE_SIZE {
+                return Err(StorageError::StorageLimit);
             }
-            file.metadata.last_modified = get_current_timestamp();
-            Ok(true)
+
+            let sha256 = Sha256::digest(&content).into();
+            let chunks = storage.store_content(&content)?;
+            Ok(storage.push_version(caller, metadata, chunks, content.len(), sha256))
         } else {
             Err(StorageError::FileNotFound)
         }
     })
 }
 
+#[query]
+fn list_versions(name: String) -> Result<Vec<FileVersion>, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let storage = state.borrow();
+        let metadata = storage.unexpired_file(&name)?;
+        authorize_access(caller, &metadata, ShareRole::Read)?;
+        Ok(metadata.version_history)
+    })
+}
+
+#[query]
+fn download_version(name: String, version: u64) -> Result<Vec<u8>, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let storage = state.borrow();
+        let metadata = storage.unexpired_file(&name)?;
+        authorize_access(caller, &metadata, ShareRole::Read)?;
+
+        let entry = find_version(&metadata, version)?;
+        storage.read_version_content(entry)
+    })
+}
+
+// Makes an older version's content the new latest version; history is never rewritten.
+// The chunks are shared, so this costs no storage. Returns the new version number.
 #[update]
-fn create_file_version(name: String, content: Vec<u8>) -> Result<bool, StorageError> {
+fn restore_version(name: String, version: u64) -> Result<u64, StorageError> {
+    let caller = ic_cdk::caller();
     STATE.with(|state| {
         let mut storage = state.borrow_mut();
-        
-        if let Some(file) = storage.files.get_mut(&name) {
-            let version_id = format!("{}_{}", name, get_current_timestamp());
-            file.metadata.version_history.push(version_id.clone());
-            
-            // Store the old version in chunks
-            let chunks: Vec<Vec<u8>> = content
-                .chunks(CHUNK_SIZE)
-                .map(|chunk| chunk.to_vec())
-                .collect();
-                
-            storage.file_chunks.insert(version_id, chunks);
-            Ok(true)
-        } else {
-            Err(StorageError::FileNotFound)
+        let metadata = storage.files.get(&name).ok_or(StorageError::FileNotFound)?;
+        authorize_access(caller, &metadata, ShareRole::Write)?;
+        storage.check_retention(&metadata)?;
+        check_version_room(&metadata)?;
+
+        let entry = find_version(&metadata, version)?;
+        let (chunks, size, sha256) = (entry.chunks.clone(), entry.size, entry.sha256);
+        storage.retain_chunks(&chunks);
+        Ok(storage.push_version(caller, metadata, chunks, size, sha256))
+    })
+}
+
+// Drops an old version, freeing chunks no other version uses. The live version
+// cannot be deleted.
+#[update]
+fn delete_version(name: String, version: u64) -> Result<bool, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+        let mut metadata = storage.files.get(&name).ok_or(StorageError::FileNotFound)?;
+        authorize_access(caller, &metadata, ShareRole::Admin)?;
+        storage.check_retention(&metadata)?;
+
+        if version == metadata.current_version {
+            return Err(StorageError::InvalidOperation);
         }
+        let chunks = find_version(&metadata, version)?.chunks.clone();
+
+        storage.release_chunks(&chunks);
+        metadata
+            .version_history
+            .retain(|entry| entry.version != version);
+        touch(&mut metadata);
+        storage.put_file(metadata);
+        Ok(true)
+    })
+}
+
+// Grants `principal` the given role on a file, replacing any role it already had
+#[update]
+fn share_file(name: String, principal: Principal, role: ShareRole) -> Result<bool, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+
+        let mut metadata = storage.files.get(&name).ok_or(StorageError::FileNotFound)?;
+        authorize_access(caller, &metadata, ShareRole::Admin)?;
+        if principal == metadata.owner {
+            return Err(StorageError::InvalidOperation);
+        }
+
+        metadata.shares.retain(|share| share.principal != principal);
+        metadata.shares.push(ShareEntry { principal, role });
+        touch(&mut metadata);
+        storage.put_file(metadata);
+        Ok(true)
+    })
+}
+
+#[update]
+fn revoke_share(name: String, principal: Principal) -> Result<bool, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+
+        let mut metadata = storage.files.get(&name).ok_or(StorageError::FileNotFound)?;
+        authorize_access(caller, &metadata, ShareRole::Admin)?;
+
+        let shares_before = metadata.shares.len();
+        metadata.shares.retain(|share| share.principal != principal);
+        if metadata.shares.len() == shares_before {
+            return Ok(false);
+        }
+        touch(&mut metadata);
+        storage.put_file(metadata);
+        Ok(true)
     })
 }
 
 #[query]
-fn search_by_tags(tags: Vec<String>) -> Vec<File> {
+fn list_shares(name: String) -> Result<Vec<ShareEntry>, StorageError> {
+    let caller = ic_cdk::caller();
     STATE.with(|state| {
         let storage = state.borrow();
-        storage
-            .files
-            .values()
-            .filter(|file| {
-                tags.iter()
-                    .all(|tag| file.metadata.tags.contains(tag))
-            })
-            .cloned()
-            .collect()
+        let metadata = storage.files.get(&name).ok_or(StorageError::FileNotFound)?;
+        authorize_access(caller, &metadata, ShareRole::Read)?;
+        Ok(metadata.shares)
     })
 }
 
@@ -205,28 +1397,128 @@ fn get_storage_analytics() -> (usize, usize, usize) {
     STATE.with(|state| {
         let storage = state.borrow();
         (
-            storage.storage_usage,
-            storage.max_storage_size,
-            storage.files.len()
+            storage.storage_usage(),
+            storage.max_storage_size(),
+            storage.files.len() as usize
         )
     })
 }
 
+// Read from the per-type counts of the type index, so no metadata is decoded
 #[query]
 fn get_file_type_distribution() -> HashMap<String, usize> {
     STATE.with(|state| {
-        let storage = state.borrow();
-        let mut distribution = HashMap::new();
-        
-        for file in storage.files.values() {
-            *distribution
-                .entry(file.metadata.file_type.clone())
-                .or_insert(0) += 1;
-        }
-        
-        distribution
+        state
+            .borrow()
+            .type_index
+            .keys_after(None)
+            .map(|(file_type, count)| (file_type, count as usize))
+            .collect()
     })
 }
 
 // This is needed for candid interface generation
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Whole chunks, one per fill byte
+    fn chunks_of(fills: &[u8]) -> Vec<u8> {
+        fills.iter().flat_map(|fill| vec![*fill; CHUNK_SIZE]).collect()
+    }
+
+    fn with_storage<R>(f: impl FnOnce(&mut FileStorage) -> R) -> R {
+        STATE.with(|state| f(&mut state.borrow_mut()))
+    }
+
+    #[test]
+    fn stores_repeated_chunks_once() {
+        with_storage(|storage| {
+            let chunks = storage.store_content(&chunks_of(&[1, 2, 1])).unwrap();
+            assert_eq!(chunks.len(), 3);
+            assert_eq!(chunks[0], chunks[2]);
+            assert_eq!(storage.chunk_refs.get(&chunks[0]), Some(2));
+            assert_eq!(storage.storage_usage(), 2 * CHUNK_SIZE);
+
+            storage.release_chunks(&chunks);
+            assert_eq!(storage.storage_usage(), 0);
+            assert_eq!(storage.file_chunks.len(), 0);
+            assert_eq!(storage.chunk_refs.len(), 0);
+        })
+    }
+
+    #[test]
+    fn frees_shared_chunks_with_their_last_reference() {
+        with_storage(|storage| {
+            let first = storage.store_content(&chunks_of(&[1, 2])).unwrap();
+            let second = storage.store_content(&chunks_of(&[2, 3])).unwrap();
+            assert_eq!(storage.storage_usage(), 3 * CHUNK_SIZE);
+
+            storage.release_chunks(&first);
+            assert_eq!(storage.storage_usage(), 2 * CHUNK_SIZE);
+            assert!(storage.read_chunk(&first[0]).is_none());
+            assert!(storage.read_chunk(&second[0]).is_some());
+
+            storage.release_chunks(&second);
+            assert_eq!(storage.storage_usage(), 0);
+            // Releasing chunks nothing holds changes nothing
+            storage.release_chunks(&second);
+            assert_eq!(storage.storage_usage(), 0);
+        })
+    }
+
+    // Usage follows unique bytes through the reference changes that copy_file,
+    // restore_version and deleting make
+    #[test]
+    fn counts_unique_bytes_through_copy_restore_and_delete() {
+        with_storage(|storage| {
+            let mut content = chunks_of(&[1]);
+            content.extend_from_slice(b"tail");
+            let first = storage.store_content(&content).unwrap();
+            let second = storage.store_content(&chunks_of(&[1, 2])).unwrap();
+            let unique = 2 * CHUNK_SIZE + 4;
+            assert_eq!(storage.storage_usage(), unique);
+
+            // Copy: the copy takes a reference on every version
+            storage.retain_chunks(&first);
+            storage.retain_chunks(&second);
+            // Restore the first version as a third one
+            storage.retain_chunks(&first);
+            assert_eq!(storage.storage_usage(), unique);
+
+            // Delete the original with all three versions; the copy keeps everything
+            storage.release_chunks(&first);
+            storage.release_chunks(&second);
+            storage.release_chunks(&first);
+            assert_eq!(storage.storage_usage(), unique);
+            assert_eq!(storage.chunk_refs.get(&first[0]), Some(2));
+
+            // Delete the copy
+            storage.release_chunks(&first);
+            storage.release_chunks(&second);
+            assert_eq!(storage.storage_usage(), 0);
+            assert_eq!(storage.file_chunks.len(), 0);
+        })
+    }
+
+    #[test]
+    fn refuses_content_over_capacity_without_storing_it() {
+        with_storage(|storage| {
+            let mut stats = storage.stats.get().clone();
+            stats.max_storage_size = 2 * CHUNK_SIZE;
+            storage.stats.set(stats).unwrap();
+
+            let result = storage.store_content(&chunks_of(&[1, 2, 3]));
+            assert!(matches!(result, Err(StorageError::StorageLimit)));
+            assert_eq!(storage.storage_usage(), 0);
+            assert_eq!(storage.file_chunks.len(), 0);
+
+            // Only new bytes count, so repeated chunks fit
+            assert!(storage.store_content(&chunks_of(&[1, 1, 2])).is_ok());
+            assert!(storage.store_content(&chunks_of(&[2, 1])).is_ok());
+            assert_eq!(storage.storage_usage(), 2 * CHUNK_SIZE);
+        })
+    }
+}
@@ -0,0 +1,220 @@
+// Metadata-only file listing with cursor pagination. Name order comes straight from the
+// files map; the other sort orders are kept in stable secondary indexes.
+use crate::expiry::is_expired;
+use crate::{can_access, FileMetadata, FileStorage, Memory, Sha256Hash, ShareRole, STATE};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::query;
+use ic_stable_structures::StableBTreeMap;
+use std::ops::Bound;
+
+const DEFAULT_PAGE_SIZE: u32 = 100;
+const MAX_PAGE_SIZE: u32 = 1000;
+// Entries examined per call, so a page stays cheap even when the caller can read
+// only a few of the files it walks past
+pub(crate) const MAX_SCAN: usize = 10_000;
+// Approximate encoded size at which a page ends early, keeping replies well under the
+// response limit
+pub(crate) const MAX_PAGE_BYTES: usize = 1024 * 1024;
+
+#[derive(CandidType, Clone, Copy, Deserialize)]
+pub(crate) enum FileSortKey {
+    Name,
+    Size,
+    UploadTimestamp,
+    LastModified,
+}
+
+// A file's place in one sort order: its sort value, ties broken by name. Serves as
+// the key of the sort indexes and as the pagination cursor (`value` is 0 for Name).
+#[derive(CandidType, Clone, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct FilePosition {
+    pub(crate) value: u64,
+    pub(crate) name: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct ListFilesRequest {
+    sort_by: FileSortKey,
+    descending: bool,
+    cursor: Option<FilePosition>, // `next_cursor` of the previous page
+    limit: Option<u32>,
+}
+
+// What listings show of a file: the metadata of bounded size. Version history, shares,
+// attributes and metadata history are left to get_file_metadata.
+#[derive(CandidType, Deserialize)]
+pub(crate) struct FileSummary {
+    name: String,
+    size: usize,
+    upload_timestamp: u64,
+    last_modified: u64,
+    file_type: String,
+    is_encrypted: bool,
+    tags: Vec<String>,
+    current_version: u64,
+    sha256: Sha256Hash,
+    owner: Principal,
+    revision: u64,
+    expires_at: Option<u64>,
+}
+
+impl From<FileMetadata> for FileSummary {
+    fn from(metadata: FileMetadata) -> Self {
+        FileSummary {
+            name: metadata.name,
+            size: metadata.size,
+            upload_timestamp: metadata.upload_timestamp,
+            last_modified: metadata.last_modified,
+            file_type: metadata.file_type,
+            is_encrypted: metadata.is_encrypted,
+            tags: metadata.tags,
+            current_version: metadata.current_version,
+            sha256: metadata.sha256,
+            owner: metadata.owner,
+            revision: metadata.revision,
+            expires_at: metadata.expires_at,
+        }
+    }
+}
+
+impl FileSummary {
+    // Rough upper bound of the summary's encoded size
+    pub(crate) fn encoded_size(&self) -> usize {
+        let strings: usize = self.tags.iter().map(|tag| tag.len() + 8).sum();
+        128 + self.name.len() + self.file_type.len() + strings
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct FilePage {
+    files: Vec<FileSummary>,
+    next_cursor: Option<FilePosition>, // None once the listing is complete
+}
+
+fn position(value: u64, metadata: &FileMetadata) -> FilePosition {
+    FilePosition {
+        value,
+        name: metadata.name.clone(),
+    }
+}
+
+// Page size for a requested limit, within [1, MAX_PAGE_SIZE]
+pub(crate) fn page_limit(limit: Option<u32>) -> usize {
+    limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize
+}
+
+// Walks `positions` collecting up to `limit` unexpired files that the caller can read
+// and that satisfy `matches`. Stops after MAX_SCAN positions or MAX_PAGE_BYTES of
+// summaries; `next_cursor` resumes the walk.
+pub(crate) fn collect_page(
+    storage: &FileStorage,
+    caller: Principal,
+    positions: impl Iterator<Item = FilePosition>,
+    limit: usize,
+    matches: impl Fn(&FileMetadata) -> bool,
+) -> FilePage {
+    let mut positions = positions.peekable();
+    let mut files = Vec::new();
+    let mut last = None;
+    let mut scanned = 0;
+    let mut bytes = 0;
+    while files.len() < limit && scanned < MAX_SCAN && bytes < MAX_PAGE_BYTES {
+        let position = match positions.next() {
+            Some(position) => position,
+            None => break,
+        };
+        scanned += 1;
+        if let Some(metadata) = storage.files.get(&position.name) {
+            if can_access(caller, &metadata, ShareRole::Read)
+                && !is_expired(&metadata)
+                && matches(&metadata)
+            {
+                let summary = FileSummary::from(metadata);
+                bytes += summary.encoded_size();
+                files.push(summary);
+            }
+        }
+        last = Some(position);
+    }
+
+    let next_cursor = if positions.peek().is_some() { last } else { None };
+    FilePage { files, next_cursor }
+}
+
+impl FileStorage {
+    pub(crate) fn index_sort_keys(&mut self, metadata: &FileMetadata) {
+        self.size_index.insert(position(metadata.size as u64, metadata), ());
+        self.uploaded_index
+            .insert(position(metadata.upload_timestamp, metadata), ());
+        self.modified_index
+            .insert(position(metadata.last_modified, metadata), ());
+    }
+
+    pub(crate) fn unindex_sort_keys(&mut self, metadata: &FileMetadata) {
+        self.size_index.remove(&position(metadata.size as u64, metadata));
+        self.uploaded_index
+            .remove(&position(metadata.upload_timestamp, metadata));
+        self.modified_index
+            .remove(&position(metadata.last_modified, metadata));
+    }
+
+    pub(crate) fn sort_index(&self, sort_by: FileSortKey) -> Option<&StableBTreeMap<FilePosition, (), Memory>> {
+        match sort_by {
+            FileSortKey::Name => None,
+            FileSortKey::Size => Some(&self.size_index),
+            FileSortKey::UploadTimestamp => Some(&self.uploaded_index),
+            FileSortKey::LastModified => Some(&self.modified_index),
+        }
+    }
+
+    // Every file position in the requested order, starting after `cursor`
+    pub(crate) fn positions(
+        &self,
+        sort_by: FileSortKey,
+        descending: bool,
+        cursor: Option<FilePosition>,
+    ) -> Box<dyn Iterator<Item = FilePosition> + '_> {
+        let bounds = match (cursor, descending) {
+            (None, _) => (Bound::Unbounded, Bound::Unbounded),
+            (Some(cursor), false) => (Bound::Excluded(cursor), Bound::Unbounded),
+            (Some(cursor), true) => (Bound::Unbounded, Bound::Excluded(cursor)),
+        };
+
+        match self.sort_index(sort_by) {
+            Some(index) => {
+                let keys = index.keys_range(bounds);
+                if descending {
+                    Box::new(keys.rev())
+                } else {
+                    Box::new(keys)
+                }
+            }
+            None => {
+                let bounds = (
+                    bounds.0.map(|cursor| cursor.name),
+                    bounds.1.map(|cursor| cursor.name),
+                );
+                let names = self.files.keys_range(bounds);
+                let to_position = |name| FilePosition { value: 0, name };
+                if descending {
+                    Box::new(names.rev().map(to_position))
+                } else {
+                    Box::new(names.map(to_position))
+                }
+            }
+        }
+    }
+}
+
+// One page of the files the caller can read. A page may hold fewer than `limit`
+// files, or none, while `next_cursor` is still set; keep paging until it is None.
+#[query]
+fn list_files(request: ListFilesRequest) -> FilePage {
+    let caller = ic_cdk::caller();
+    let limit = page_limit(request.limit);
+    STATE.with(|state| {
+        let storage = state.borrow();
+        let positions = storage.positions(request.sort_by, request.descending, request.cursor);
+        collect_page(&storage, caller, positions, limit, |_| true)
+    })
+}
@@ -0,0 +1,1119 @@
+// Compatibility with stable memory written by earlier versions of the canister.
+//
+// Records whose type gained fields since it was first stored are decoded through a
+// "stored" mirror in which every added field is optional: Candid decodes a missing
+// field into None, while a required field that is missing fails to decode and would
+// trap post_upgrade. New fields must be added to the mirror as Option, with a default
+// below, and a test that decodes a record stored before the field existed. Anything that cannot be defaulted while decoding is repaired once by
+// `migrate` in post_upgrade, driven by the schema version kept in its own cell.
+//
+// Before chunks became content-addressed, the files map held whole `File` records with
+// the live content inline, and memory 1 held chunks per file (and, later, per version).
+// Those records are taken out of the files map before STATE first decodes it, then
+// stored again the current way. Memory 1 is emptied afterwards; this version of
+// ic-stable-structures cannot hand its pages back, so they stay allocated.
+//
+// Secondary indexes only cover files stored after the index was introduced, so a
+// migration also rebuilds them: a timer walks the files map in batches, resuming from a
+// cursor kept in the schema cell. Indexing a file twice is harmless.
+use crate::attributes::FileAttribute;
+use crate::patch::MetadataChange;
+use crate::trash::{TrashedFile, DEFAULT_TRASH_RETENTION};
+use crate::{
+    chunk_count_for, get_memory, split_chunks, FileMetadata, FileStorage, FileVersion, Memory,
+    Sha256Hash, ShareEntry, StorageStats, UploadSession, FILES_MEMORY_ID, LEGACY_CHUNKS_MEMORY_ID,
+    SCHEMA_MEMORY_ID, STATE,
+};
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::storable;
+use ic_stable_structures::{Memory as _, StableBTreeMap, StableCell, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::time::Duration;
+
+// Bump when stored data needs a one-off migration, and add the step to `migrate`
+const SCHEMA_VERSION: u32 = 1;
+
+// Stands in for a hash that records from before hashing was added lack; replaced
+// with the real hash by `migrate`
+const MISSING_HASH: Sha256Hash = [0; 32];
+
+// Files reindexed per timer tick. Text indexing can read and tokenize up to 1MB per
+// file, so batches stay small to keep a tick within the instruction limit.
+const REINDEX_BATCH: usize = 10;
+
+// Encodes the current type; decodes the stored mirror and converts it
+macro_rules! impl_migrating_storable {
+    ($($t:ty => $stored:ty),*) => {
+        $(
+            impl Storable for $t {
+                fn to_bytes(&self) -> Cow<'_, [u8]> {
+                    Cow::Owned(Encode!(self).expect("failed to encode stable value"))
+                }
+
+                fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                    Decode!(bytes.as_ref(), $stored)
+                        .expect("failed to decode stable value")
+                        .into()
+                }
+
+                const BOUND: storable::Bound = storable::Bound::Unbounded;
+            }
+        )*
+    };
+}
+
+impl_migrating_storable!(
+    FileMetadata => StoredFileMetadata,
+    UploadSession => StoredUploadSession,
+    TrashedFile => StoredTrashedFile,
+    StorageStats => StoredStorageStats
+);
+
+#[derive(CandidType, Deserialize)]
+struct SchemaState {
+    version: u32,             // 0 for stable memory written before versioning
+    reindex: Option<Reindex>, // Set while the indexes are being rebuilt
+}
+
+#[derive(CandidType, Clone, Deserialize)]
+struct Reindex {
+    after: Option<String>, // Last file reindexed
+}
+
+impl Storable for SchemaState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode stable value"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode stable value")
+    }
+
+    const BOUND: storable::Bound = storable::Bound::Unbounded;
+}
+
+thread_local! {
+    // Kept apart from STATE, so it can be read before anything else is decoded
+    static SCHEMA: RefCell<StableCell<SchemaState, Memory>> = RefCell::new(
+        StableCell::init(
+            get_memory(SCHEMA_MEMORY_ID),
+            SchemaState {
+                version: 0,
+                reindex: None,
+            },
+        )
+        .expect("failed to initialize schema version"),
+    );
+}
+
+fn schema_version() -> u32 {
+    SCHEMA.with(|schema| schema.borrow().get().version)
+}
+
+fn reindex_progress() -> Option<Reindex> {
+    SCHEMA.with(|schema| schema.borrow().get().reindex.clone())
+}
+
+fn set_schema(version: u32, reindex: Option<Reindex>) {
+    SCHEMA.with(|schema| {
+        schema
+            .borrow_mut()
+            .set(SchemaState { version, reindex })
+            .expect("failed to persist schema version");
+    });
+}
+
+// A fresh install needs no migration
+pub(crate) fn init_schema() {
+    set_schema(SCHEMA_VERSION, None);
+}
+
+// Brings stable memory written by an earlier version up to the current schema, then
+// resumes rebuilding the indexes if a migration started that. Timers do not survive
+// upgrades, so this runs on every post_upgrade.
+pub(crate) fn migrate() {
+    if schema_version() < SCHEMA_VERSION {
+        let legacy = take_legacy_files();
+        STATE.with(|state| {
+            let mut storage = state.borrow_mut();
+            if !legacy.is_empty() {
+                storage.import_legacy_files(legacy);
+            }
+            storage.repair_hashes();
+        });
+        let reindex = reindex_progress().unwrap_or(Reindex { after: None });
+        set_schema(SCHEMA_VERSION, Some(reindex));
+    }
+    if reindex_progress().is_some() {
+        schedule_reindex();
+    }
+}
+
+fn schedule_reindex() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        let done = STATE.with(|state| state.borrow_mut().reindex_batch());
+        if !done {
+            schedule_reindex();
+        }
+    });
+}
+
+#[derive(CandidType, Deserialize)]
+struct StoredFileVersion {
+    version: u64,
+    size: usize,
+    author: Principal,
+    created_at: u64,
+    sha256: Option<Sha256Hash>,
+    chunks: Vec<Sha256Hash>,
+}
+
+impl From<StoredFileVersion> for FileVersion {
+    fn from(stored: StoredFileVersion) -> Self {
+        FileVersion {
+            version: stored.version,
+            size: stored.size,
+            author: stored.author,
+            created_at: stored.created_at,
+            sha256: stored.sha256.unwrap_or(MISSING_HASH),
+            chunks: stored.chunks,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct StoredFileMetadata {
+    name: String,
+    size: usize,
+    upload_timestamp: u64,
+    last_modified: u64,
+    file_type: String,
+    is_encrypted: bool,
+    version_history: Vec<StoredFileVersion>,
+    current_version: Option<u64>,
+    tags: Vec<String>,
+    chunk_count: Option<u32>,
+    sha256: Option<Sha256Hash>,
+    owner: Option<Principal>,
+    shares: Option<Vec<ShareEntry>>,
+    revision: Option<u64>,
+    attributes: Option<Vec<FileAttribute>>,
+    metadata_history: Option<Vec<MetadataChange>>,
+    expires_at: Option<u64>,
+    retain_until: Option<u64>,
+    legal_hold: Option<bool>,
+}
+
+impl From<StoredFileMetadata> for FileMetadata {
+    fn from(stored: StoredFileMetadata) -> Self {
+        let version_history: Vec<FileVersion> =
+            stored.version_history.into_iter().map(Into::into).collect();
+        let current_version = stored
+            .current_version
+            .or_else(|| version_history.last().map(|version| version.version))
+            .unwrap_or(1);
+        FileMetadata {
+            chunk_count: stored.chunk_count.unwrap_or(chunk_count_for(stored.size)),
+            name: stored.name,
+            size: stored.size,
+            upload_timestamp: stored.upload_timestamp,
+            last_modified: stored.last_modified,
+            file_type: stored.file_type,
+            is_encrypted: stored.is_encrypted,
+            version_history,
+            current_version,
+            tags: stored.tags,
+            sha256: stored.sha256.unwrap_or(MISSING_HASH),
+            // Files without a recorded owner are left to controllers
+            owner: stored.owner.unwrap_or(Principal::management_canister()),
+            shares: stored.shares.unwrap_or_default(),
+            revision: stored.revision.unwrap_or(1),
+            attributes: stored.attributes.unwrap_or_default(),
+            metadata_history: stored.metadata_history.unwrap_or_default(),
+            expires_at: stored.expires_at,
+            retain_until: stored.retain_until,
+            legal_hold: stored.legal_hold.unwrap_or(false),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct StoredUploadSession {
+    owner: Option<Principal>,
+    name: String,
+    file_type: String,
+    tags: Vec<String>,
+    total_size: usize,
+    chunk_count: u32,
+    received: Vec<bool>,
+    started_at: u64,
+    overwrite: Option<bool>,
+    expires_at: Option<u64>,
+    reserved: Option<usize>,
+}
+
+impl From<StoredUploadSession> for UploadSession {
+    fn from(stored: StoredUploadSession) -> Self {
+        UploadSession {
+            // Sessions without a recorded owner can only be aborted by controllers
+            owner: stored.owner.unwrap_or(Principal::management_canister()),
+            name: stored.name,
+            file_type: stored.file_type,
+            tags: stored.tags,
+            total_size: stored.total_size,
+            chunk_count: stored.chunk_count,
+            received: stored.received,
+            started_at: stored.started_at,
+            overwrite: stored.overwrite.unwrap_or(false),
+            expires_at: stored.expires_at,
+            // Sessions begun before reservations reserved nothing
+            reserved: stored.reserved.unwrap_or(0),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct StoredTrashedFile {
+    metadata: StoredFileMetadata,
+    deleted_at: u64,
+    deleted_by: Principal,
+}
+
+impl From<StoredTrashedFile> for TrashedFile {
+    fn from(stored: StoredTrashedFile) -> Self {
+        TrashedFile {
+            metadata: stored.metadata.into(),
+            deleted_at: stored.deleted_at,
+            deleted_by: stored.deleted_by,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct StoredStorageStats {
+    storage_usage: usize,
+    max_storage_size: usize,
+    next_upload_id: Option<u64>,
+    next_trash_id: Option<u64>,
+    trash_retention: Option<u64>,
+    reserved_bytes: Option<usize>,
+}
+
+impl From<StoredStorageStats> for StorageStats {
+    fn from(stored: StoredStorageStats) -> Self {
+        StorageStats {
+            storage_usage: stored.storage_usage,
+            max_storage_size: stored.max_storage_size,
+            next_upload_id: stored.next_upload_id.unwrap_or(0),
+            next_trash_id: stored.next_trash_id.unwrap_or(0),
+            trash_retention: stored.trash_retention.unwrap_or(DEFAULT_TRASH_RETENTION),
+            reserved_bytes: stored.reserved_bytes.unwrap_or(0),
+        }
+    }
+}
+
+// A file as stored before chunks became content-addressed
+#[derive(CandidType, Deserialize)]
+struct LegacyFile {
+    content: Vec<u8>, // Live content
+    metadata: LegacyMetadata,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegacyMetadata {
+    name: String,
+    upload_timestamp: u64,
+    last_modified: u64,
+    file_type: String,
+    is_encrypted: bool,
+    // Numbered versions with chunks in memory 1; None while versions were bare ids,
+    // which Candid decodes as a type mismatch under `opt`
+    version_history: Option<Vec<LegacyVersion>>,
+    current_version: Option<u64>,
+    tags: Vec<String>,
+    owner: Option<Principal>,
+    shares: Option<Vec<ShareEntry>>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegacyVersion {
+    version: u64,
+    size: usize,
+    author: Principal,
+    created_at: u64,
+}
+
+// Key of memory 1; versions were added to it along with numbered versions
+#[derive(CandidType, Deserialize)]
+struct LegacyChunkKey {
+    file: String,
+    version: Option<u64>,
+    index: u32,
+}
+
+impl LegacyFile {
+    fn history(&self) -> &[LegacyVersion] {
+        self.metadata.version_history.as_deref().unwrap_or_default()
+    }
+
+    fn current_version(&self) -> u64 {
+        self.metadata.current_version.unwrap_or(1)
+    }
+
+    // Bytes the file counted towards storage usage: every version in full
+    fn counted_size(&self) -> usize {
+        match self.history() {
+            [] => self.content.len(),
+            history => history.iter().map(|version| version.size).sum(),
+        }
+    }
+}
+
+// Takes the legacy records out of the files map, reading it as raw bytes so nothing
+// is decoded as FileMetadata. Must run before STATE is first used.
+fn take_legacy_files() -> Vec<LegacyFile> {
+    // Memory 1 was created on install by every version that stored legacy records
+    if get_memory(LEGACY_CHUNKS_MEMORY_ID).size() == 0 {
+        return Vec::new();
+    }
+    let mut files: StableBTreeMap<String, Vec<u8>, Memory> =
+        StableBTreeMap::init(get_memory(FILES_MEMORY_ID));
+    let legacy: Vec<(String, LegacyFile)> = files
+        .iter()
+        .filter(|(_, bytes)| Decode!(bytes, StoredFileMetadata).is_err())
+        .map(|(name, bytes)| {
+            let file = Decode!(&bytes, LegacyFile).expect("failed to decode legacy file");
+            (name, file)
+        })
+        .collect();
+    for (name, _) in &legacy {
+        files.remove(name);
+    }
+    legacy.into_iter().map(|(_, file)| file).collect()
+}
+
+// Chunks of one legacy version, read from memory 1 in index order
+struct LegacyChunks {
+    chunks: Vec<Sha256Hash>,
+    size: usize,
+    hasher: Sha256,
+    complete: bool, // False if an index was skipped
+}
+
+impl FileStorage {
+    // Stores legacy files the current way, keeping their numbered versions. Storage
+    // usage is recounted, as chunks shared between versions are now stored once.
+    fn import_legacy_files(&mut self, legacy: Vec<LegacyFile>) {
+        let counted: usize = legacy.iter().map(LegacyFile::counted_size).sum();
+        self.set_storage_usage(self.storage_usage().saturating_sub(counted));
+
+        let mut versions = self.import_legacy_chunks(&legacy);
+        for file in legacy {
+            let metadata = self.import_legacy_file(file, &mut versions);
+            // Indexed by the rebuild that follows the migration
+            self.files.insert(metadata.name.clone(), metadata);
+        }
+        // Chunks of versions no file lists anymore
+        for version in versions.into_values() {
+            self.release_chunks(&version.chunks);
+        }
+        StableBTreeMap::<Vec<u8>, Vec<u8>, Memory>::new(get_memory(LEGACY_CHUNKS_MEMORY_ID));
+    }
+
+    // Moves the chunks of every non-live legacy version into the chunk store. The live
+    // content is taken from the file record instead.
+    fn import_legacy_chunks(
+        &mut self,
+        legacy: &[LegacyFile],
+    ) -> HashMap<(String, u64), FileVersion> {
+        let wanted: HashMap<(String, u64), &LegacyVersion> = legacy
+            .iter()
+            .flat_map(|file| {
+                file.history()
+                    .iter()
+                    .filter(|version| version.version != file.current_version())
+                    .map(|version| ((file.metadata.name.clone(), version.version), version))
+            })
+            .collect();
+        let mut found: HashMap<(String, u64), LegacyChunks> = HashMap::new();
+
+        let chunks: StableBTreeMap<Vec<u8>, Vec<u8>, Memory> =
+            StableBTreeMap::init(get_memory(LEGACY_CHUNKS_MEMORY_ID));
+        for (key, chunk) in chunks.iter() {
+            let (key, index) = match Decode!(&key, LegacyChunkKey) {
+                Ok(LegacyChunkKey {
+                    file,
+                    version: Some(version),
+                    index,
+                }) => ((file, version), index),
+                _ => continue,
+            };
+            if !wanted.contains_key(&key) {
+                continue;
+            }
+            let entry = found.entry(key).or_insert_with(|| LegacyChunks {
+                chunks: Vec::new(),
+                size: 0,
+                hasher: Sha256::new(),
+                complete: true,
+            });
+            // Chunks come in key order, so each version's chunks arrive by index
+            entry.complete &= index as usize == entry.chunks.len();
+            entry.hasher.update(&chunk);
+            entry.size += chunk.len();
+            let hash = Sha256::digest(&chunk).into();
+            entry.chunks.extend(self.put_chunks(&[(hash, &chunk)]));
+        }
+
+        let mut versions = HashMap::new();
+        for (key, entry) in found {
+            let legacy = wanted[&key];
+            if !entry.complete || entry.size != legacy.size {
+                self.release_chunks(&entry.chunks);
+                continue;
+            }
+            let version = FileVersion {
+                version: legacy.version,
+                size: legacy.size,
+                author: legacy.author,
+                created_at: legacy.created_at,
+                sha256: entry.hasher.finalize().into(),
+                chunks: entry.chunks,
+            };
+            versions.insert(key, version);
+        }
+        versions
+    }
+
+    // Builds current metadata for a legacy file. Versions whose chunks were lost are
+    // dropped from the history; the live content always survives.
+    fn import_legacy_file(
+        &mut self,
+        file: LegacyFile,
+        versions: &mut HashMap<(String, u64), FileVersion>,
+    ) -> FileMetadata {
+        let current_version = file.current_version();
+        let owner = file
+            .metadata
+            .owner
+            .unwrap_or(Principal::management_canister());
+        let sha256: Sha256Hash = Sha256::digest(&file.content).into();
+        let chunks = self.put_chunks(&split_chunks(&file.content));
+        let live = FileVersion {
+            version: current_version,
+            size: file.content.len(),
+            author: owner,
+            created_at: file.metadata.last_modified,
+            sha256,
+            chunks,
+        };
+
+        let name = file.metadata.name.clone();
+        let mut version_history = Vec::new();
+        let mut live = Some(live);
+        for legacy in file.history() {
+            if legacy.version == current_version {
+                if let Some(mut live) = live.take() {
+                    live.author = legacy.author;
+                    live.created_at = legacy.created_at;
+                    version_history.push(live);
+                }
+            } else if let Some(version) = versions.remove(&(name.clone(), legacy.version)) {
+                version_history.push(version);
+            }
+        }
+        version_history.extend(live);
+
+        let LegacyMetadata {
+            name,
+            upload_timestamp,
+            last_modified,
+            file_type,
+            is_encrypted,
+            tags,
+            shares,
+            ..
+        } = file.metadata;
+        let size = file.content.len();
+        FileMetadata {
+            name,
+            size,
+            upload_timestamp,
+            last_modified,
+            file_type,
+            is_encrypted,
+            version_history,
+            current_version,
+            tags,
+            chunk_count: chunk_count_for(size),
+            sha256,
+            owner,
+            shares: shares.unwrap_or_default(),
+            revision: 1,
+            attributes: Vec::new(),
+            metadata_history: Vec::new(),
+            expires_at: None,
+            retain_until: None,
+            legal_hold: false,
+        }
+    }
+
+    // Indexes the next REINDEX_BATCH files. Returns true once every file is indexed.
+    fn reindex_batch(&mut self) -> bool {
+        let reindex = match reindex_progress() {
+            Some(reindex) => reindex,
+            None => return true,
+        };
+        let start = match reindex.after {
+            Some(name) => Bound::Excluded(name),
+            None => Bound::Unbounded,
+        };
+        let batch: Vec<FileMetadata> = self
+            .files
+            .range((start, Bound::Unbounded))
+            .take(REINDEX_BATCH)
+            .map(|(_, metadata)| metadata)
+            .collect();
+        for metadata in &batch {
+            self.index_sort_keys(metadata);
+            self.index_postings(metadata);
+            self.index_expiry(metadata);
+            self.index_text(metadata);
+        }
+
+        let done = batch.len() < REINDEX_BATCH;
+        let reindex = match (done, batch.last()) {
+            (false, Some(last)) => Some(Reindex {
+                after: Some(last.name.clone()),
+            }),
+            _ => None,
+        };
+        set_schema(SCHEMA_VERSION, reindex);
+        done
+    }
+
+    // Computes the hashes that files stored before hashing was added lack
+    fn repair_hashes(&mut self) {
+        let names: Vec<String> = self
+            .files
+            .iter()
+            .filter(|(_, metadata)| {
+                metadata.sha256 == MISSING_HASH
+                    || metadata
+                        .version_history
+                        .iter()
+                        .any(|version| version.sha256 == MISSING_HASH)
+            })
+            .map(|(name, _)| name)
+            .collect();
+        for name in names {
+            let mut metadata = match self.files.get(&name) {
+                Some(metadata) => metadata,
+                None => continue,
+            };
+            for index in 0..metadata.version_history.len() {
+                let version = &metadata.version_history[index];
+                if version.sha256 != MISSING_HASH {
+                    continue;
+                }
+                // Chunks that went missing leave the hash unknown rather than wrong
+                if let Ok(content) = self.read_version_content(version) {
+                    metadata.version_history[index].sha256 = Sha256::digest(&content).into();
+                }
+            }
+            if let Some(live) = metadata
+                .version_history
+                .iter()
+                .find(|version| version.version == metadata.current_version)
+            {
+                metadata.sha256 = live.sha256;
+            }
+            // Only hashes changed, so no index needs updating
+            self.files.insert(name, metadata);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attributes::AttributeValue;
+    use candid::types::value::{IDLArgs, IDLField, IDLValue};
+
+    // Encodes `value` with the named record fields left out at any depth, as stable
+    // memory written before those fields existed holds it
+    fn encode_without<T: CandidType>(value: &T, fields: &[&str]) -> Vec<u8> {
+        let ids: Vec<u32> = fields.iter().map(|field| candid::idl_hash(field)).collect();
+        let args = IDLArgs::from_bytes(&Encode!(value).unwrap()).unwrap();
+        let values: Vec<IDLValue> = args
+            .args
+            .into_iter()
+            .map(|value| strip(value, &ids))
+            .collect();
+        IDLArgs::new(&values).to_bytes().unwrap()
+    }
+
+    fn strip(value: IDLValue, ids: &[u32]) -> IDLValue {
+        match value {
+            IDLValue::Record(fields) => IDLValue::Record(
+                fields
+                    .into_iter()
+                    .filter(|field| !ids.contains(&field.id.get_id()))
+                    .map(|field| IDLField {
+                        id: field.id,
+                        val: strip(field.val, ids),
+                    })
+                    .collect(),
+            ),
+            IDLValue::Vec(items) => {
+                IDLValue::Vec(items.into_iter().map(|item| strip(item, ids)).collect())
+            }
+            IDLValue::Opt(inner) => IDLValue::Opt(Box::new(strip(*inner, ids))),
+            other => other,
+        }
+    }
+
+    fn decode<T: Storable>(bytes: Vec<u8>) -> T {
+        T::from_bytes(Cow::Owned(bytes))
+    }
+
+    fn sample_stats() -> StorageStats {
+        StorageStats {
+            storage_usage: 300,
+            max_storage_size: 1000,
+            next_upload_id: 7,
+            next_trash_id: 9,
+            trash_retention: 60,
+            reserved_bytes: 50,
+        }
+    }
+
+    fn sample_session() -> UploadSession {
+        UploadSession {
+            owner: Principal::from_slice(&[1]),
+            name: "big.bin".to_string(),
+            file_type: "application/octet-stream".to_string(),
+            tags: Vec::new(),
+            total_size: 50,
+            chunk_count: 1,
+            received: vec![true],
+            started_at: 10,
+            overwrite: true,
+            expires_at: Some(100),
+            reserved: 50,
+        }
+    }
+
+    // Storage stats and upload sessions as stored before reservations
+    #[test]
+    fn decodes_uploads_stored_before_reservations() {
+        let stats: StorageStats = decode(encode_without(&sample_stats(), &["reserved_bytes"]));
+        assert_eq!(stats.reserved_bytes, 0);
+        assert_eq!(stats.next_upload_id, 7);
+
+        let session: UploadSession = decode(encode_without(&sample_session(), &["reserved"]));
+        assert_eq!(session.reserved, 0);
+        assert_eq!(session.total_size, 50);
+    }
+
+    // Storage stats as stored before upload sessions
+    #[test]
+    fn decodes_stats_stored_before_upload_ids() {
+        let fields = [
+            "next_upload_id",
+            "next_trash_id",
+            "trash_retention",
+            "reserved_bytes",
+        ];
+        let stats: StorageStats = decode(encode_without(&sample_stats(), &fields));
+        assert_eq!(stats.next_upload_id, 0);
+        assert_eq!(stats.storage_usage, 300);
+        assert_eq!(stats.max_storage_size, 1000);
+    }
+
+    // FileMetadata as first stored on its own, before hashes, revisions and the rest
+    #[derive(CandidType)]
+    struct EarlyVersion {
+        version: u64,
+        size: usize,
+        author: Principal,
+        created_at: u64,
+        chunks: Vec<Sha256Hash>,
+    }
+
+    #[derive(CandidType)]
+    struct EarlyMetadata {
+        name: String,
+        size: usize,
+        upload_timestamp: u64,
+        last_modified: u64,
+        file_type: String,
+        is_encrypted: bool,
+        version_history: Vec<EarlyVersion>,
+        current_version: u64,
+        tags: Vec<String>,
+        chunk_count: u32,
+        owner: Principal,
+        shares: Vec<ShareEntry>,
+    }
+
+    #[test]
+    fn decodes_metadata_missing_later_fields() {
+        let owner = Principal::from_slice(&[1]);
+        let early = EarlyMetadata {
+            name: "a.txt".to_string(),
+            size: 3,
+            upload_timestamp: 10,
+            last_modified: 20,
+            file_type: "text/plain".to_string(),
+            is_encrypted: false,
+            version_history: vec![EarlyVersion {
+                version: 2,
+                size: 3,
+                author: owner,
+                created_at: 20,
+                chunks: vec![[7; 32]],
+            }],
+            current_version: 2,
+            tags: vec!["x".to_string()],
+            chunk_count: 1,
+            owner,
+            shares: Vec::new(),
+        };
+        let bytes = Encode!(&early).unwrap();
+        let metadata = FileMetadata::from_bytes(Cow::Owned(bytes));
+
+        assert_eq!(metadata.owner, owner);
+        assert_eq!(metadata.current_version, 2);
+        assert_eq!(metadata.version_history[0].sha256, MISSING_HASH);
+        assert_eq!(metadata.sha256, MISSING_HASH);
+        assert_eq!(metadata.revision, 1);
+        assert!(!metadata.legal_hold);
+        assert!(metadata.expires_at.is_none());
+    }
+
+    // A file record from before owners and numbered versions
+    #[derive(CandidType)]
+    struct FirstMetadata {
+        name: String,
+        size: usize,
+        upload_timestamp: u64,
+        last_modified: u64,
+        file_type: String,
+        is_encrypted: bool,
+        version_history: Vec<String>,
+        tags: Vec<String>,
+    }
+
+    #[derive(CandidType)]
+    struct FirstFile {
+        name: String,
+        content: Vec<u8>,
+        metadata: FirstMetadata,
+    }
+
+    #[test]
+    fn decodes_first_file_records_as_legacy() {
+        let first = FirstFile {
+            name: "a.txt".to_string(),
+            content: b"abc".to_vec(),
+            metadata: FirstMetadata {
+                name: "a.txt".to_string(),
+                size: 3,
+                upload_timestamp: 10,
+                last_modified: 20,
+                file_type: "text/plain".to_string(),
+                is_encrypted: false,
+                version_history: vec!["v1".to_string()],
+                tags: Vec::new(),
+            },
+        };
+        let bytes = Encode!(&first).unwrap();
+
+        assert!(Decode!(&bytes, StoredFileMetadata).is_err());
+        let legacy = Decode!(&bytes, LegacyFile).unwrap();
+        assert!(legacy.metadata.version_history.is_none());
+        assert!(legacy.metadata.owner.is_none());
+        assert_eq!(legacy.current_version(), 1);
+        assert_eq!(legacy.counted_size(), 3);
+    }
+
+    // Upload sessions as stored before owners
+    #[test]
+    fn decodes_sessions_stored_before_owners() {
+        let fields = ["owner", "overwrite", "expires_at", "reserved"];
+        let session: UploadSession = decode(encode_without(&sample_session(), &fields));
+        assert_eq!(session.owner, Principal::management_canister());
+        assert_eq!(session.name, "big.bin");
+    }
+
+    fn sample_file() -> FileMetadata {
+        let owner = Principal::from_slice(&[1]);
+        FileMetadata {
+            name: "docs/a.txt".to_string(),
+            size: 3,
+            upload_timestamp: 10,
+            last_modified: 20,
+            file_type: "text/plain".to_string(),
+            is_encrypted: false,
+            version_history: vec![FileVersion {
+                version: 1,
+                size: 3,
+                author: owner,
+                created_at: 10,
+                sha256: [5; 32],
+                chunks: vec![[7; 32]],
+            }],
+            current_version: 1,
+            tags: vec!["x".to_string()],
+            chunk_count: 1,
+            sha256: [5; 32],
+            owner,
+            shares: Vec::new(),
+            revision: 4,
+            attributes: vec![FileAttribute {
+                key: "project".to_string(),
+                value: AttributeValue::Text("alpha".to_string()),
+            }],
+            metadata_history: Vec::new(),
+            expires_at: Some(1000),
+            retain_until: Some(2000),
+            legal_hold: true,
+        }
+    }
+
+    // File records as stored before content hashes, in the file and in its versions
+    #[test]
+    fn decodes_files_stored_before_hashes() {
+        let metadata: FileMetadata = decode(encode_without(&sample_file(), &["sha256"]));
+        assert_eq!(metadata.sha256, MISSING_HASH);
+        assert_eq!(metadata.version_history[0].sha256, MISSING_HASH);
+        assert_eq!(metadata.version_history[0].chunks, vec![[7; 32]]);
+        assert_eq!(metadata.revision, 4);
+    }
+
+    // Upload sessions as stored before explicit overwrites
+    #[test]
+    fn decodes_sessions_stored_before_overwrite() {
+        let fields = ["overwrite", "expires_at", "reserved"];
+        let session: UploadSession = decode(encode_without(&sample_session(), &fields));
+        assert!(!session.overwrite);
+        assert_eq!(session.owner, Principal::from_slice(&[1]));
+    }
+
+    // File records as stored before revisions
+    #[test]
+    fn decodes_files_stored_before_revisions() {
+        let fields = [
+            "revision",
+            "attributes",
+            "metadata_history",
+            "expires_at",
+            "retain_until",
+            "legal_hold",
+        ];
+        let metadata: FileMetadata = decode(encode_without(&sample_file(), &fields));
+        assert_eq!(metadata.revision, 1);
+        assert_eq!(metadata.sha256, [5; 32]);
+    }
+
+    // File records as stored before custom attributes
+    #[test]
+    fn decodes_files_stored_before_attributes() {
+        let fields = [
+            "attributes",
+            "metadata_history",
+            "expires_at",
+            "retain_until",
+            "legal_hold",
+        ];
+        let metadata: FileMetadata = decode(encode_without(&sample_file(), &fields));
+        assert!(metadata.attributes.is_empty());
+        assert_eq!(metadata.revision, 4);
+    }
+
+    // File records as stored before metadata history
+    #[test]
+    fn decodes_files_stored_before_metadata_history() {
+        let fields = [
+            "metadata_history",
+            "expires_at",
+            "retain_until",
+            "legal_hold",
+        ];
+        let metadata: FileMetadata = decode(encode_without(&sample_file(), &fields));
+        assert!(metadata.metadata_history.is_empty());
+        assert_eq!(metadata.attributes.len(), 1);
+    }
+
+    // Storage stats as stored before the trash
+    #[test]
+    fn decodes_stats_stored_before_trash() {
+        let fields = ["next_trash_id", "trash_retention", "reserved_bytes"];
+        let stats: StorageStats = decode(encode_without(&sample_stats(), &fields));
+        assert_eq!(stats.next_trash_id, 0);
+        assert_eq!(stats.trash_retention, DEFAULT_TRASH_RETENTION);
+        assert_eq!(stats.next_upload_id, 7);
+    }
+
+    fn sample_trashed() -> TrashedFile {
+        TrashedFile {
+            metadata: sample_file(),
+            deleted_at: 30,
+            deleted_by: Principal::from_slice(&[2]),
+        }
+    }
+
+    // Files, trashed files and upload sessions as stored before expiry
+    #[test]
+    fn decodes_records_stored_before_expiry() {
+        let fields = ["expires_at", "retain_until", "legal_hold"];
+        let metadata: FileMetadata = decode(encode_without(&sample_file(), &fields));
+        assert!(metadata.expires_at.is_none());
+        assert_eq!(metadata.attributes.len(), 1);
+
+        let trashed: TrashedFile = decode(encode_without(&sample_trashed(), &fields));
+        assert!(trashed.metadata.expires_at.is_none());
+        assert_eq!(trashed.deleted_at, 30);
+
+        let fields = ["expires_at", "reserved"];
+        let session: UploadSession = decode(encode_without(&sample_session(), &fields));
+        assert!(session.expires_at.is_none());
+        assert!(session.overwrite);
+    }
+
+    // Files and trashed files as stored before retention and legal hold
+    #[test]
+    fn decodes_files_stored_before_retention() {
+        let fields = ["retain_until", "legal_hold"];
+        let metadata: FileMetadata = decode(encode_without(&sample_file(), &fields));
+        assert!(metadata.retain_until.is_none());
+        assert!(!metadata.legal_hold);
+        assert_eq!(metadata.expires_at, Some(1000));
+
+        let trashed: TrashedFile = decode(encode_without(&sample_trashed(), &fields));
+        assert!(!trashed.metadata.legal_hold);
+    }
+
+    // Key of memory 1 as the numbered version store wrote it
+    #[derive(CandidType, Clone, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+    struct VersionChunkKey {
+        file: String,
+        version: u64,
+        index: u32,
+    }
+
+    impl Storable for VersionChunkKey {
+        fn to_bytes(&self) -> Cow<'_, [u8]> {
+            Cow::Owned(Encode!(self).unwrap())
+        }
+
+        fn from_bytes(bytes: Cow<[u8]>) -> Self {
+            Decode!(bytes.as_ref(), Self).unwrap()
+        }
+
+        const BOUND: storable::Bound = storable::Bound::Unbounded;
+    }
+
+    // `history` lists (version, size) pairs; None stands for bare version ids
+    fn legacy_file(
+        name: &str,
+        content: &[u8],
+        history: Option<&[(u64, usize)]>,
+        current_version: u64,
+    ) -> LegacyFile {
+        let author = Principal::from_slice(&[1]);
+        LegacyFile {
+            content: content.to_vec(),
+            metadata: LegacyMetadata {
+                name: name.to_string(),
+                upload_timestamp: 10,
+                last_modified: 20,
+                file_type: "text/plain".to_string(),
+                is_encrypted: false,
+                version_history: history.map(|history| {
+                    history
+                        .iter()
+                        .map(|(version, size)| LegacyVersion {
+                            version: *version,
+                            size: *size,
+                            author,
+                            created_at: 10 + version,
+                        })
+                        .collect()
+                }),
+                current_version: history.map(|_| current_version),
+                tags: Vec::new(),
+                owner: Some(author),
+                shares: None,
+            },
+        }
+    }
+
+    #[test]
+    fn imports_legacy_versions_and_drops_lost_ones() {
+        let mut chunks: StableBTreeMap<VersionChunkKey, Vec<u8>, Memory> =
+            StableBTreeMap::init(get_memory(LEGACY_CHUNKS_MEMORY_ID));
+        let mut put = |file: &str, version: u64, index: u32, chunk: &[u8]| {
+            let key = VersionChunkKey {
+                file: file.to_string(),
+                version,
+                index,
+            };
+            chunks.insert(key, chunk.to_vec());
+        };
+        // Same content as the live version, so stored once
+        put("a.txt", 1, 0, b"abc");
+        // First chunk lost
+        put("a.txt", 2, 1, b"zz");
+        // Shorter than the recorded size
+        put("b.txt", 1, 0, b"old");
+        // Of a file that no longer exists
+        put("gone.txt", 1, 0, b"gone");
+
+        let legacy = vec![
+            legacy_file("a.txt", b"abc", Some(&[(1, 3), (2, 5), (3, 3)]), 3),
+            legacy_file("b.txt", b"new", Some(&[(1, 10), (2, 3)]), 2),
+            legacy_file("c.txt", b"hello", None, 1),
+        ];
+        let counted: usize = legacy.iter().map(LegacyFile::counted_size).sum();
+        assert_eq!(counted, 11 + 13 + 5);
+
+        STATE.with(|state| {
+            let mut storage = state.borrow_mut();
+            storage.set_storage_usage(counted);
+            storage.import_legacy_files(legacy);
+
+            let a = storage.files.get(&"a.txt".to_string()).unwrap();
+            let versions: Vec<u64> = a.version_history.iter().map(|v| v.version).collect();
+            assert_eq!(versions, vec![1, 3]);
+            assert_eq!(a.current_version, 3);
+            assert_eq!(a.version_history[0].chunks, a.version_history[1].chunks);
+            let abc: Sha256Hash = Sha256::digest(b"abc").into();
+            assert_eq!(a.version_history[0].sha256, abc);
+            assert_eq!(a.sha256, abc);
+            assert_eq!(storage.chunk_refs.get(&abc), Some(2));
+
+            let b = storage.files.get(&"b.txt".to_string()).unwrap();
+            let versions: Vec<u64> = b.version_history.iter().map(|v| v.version).collect();
+            assert_eq!(versions, vec![2]);
+
+            let c = storage.files.get(&"c.txt".to_string()).unwrap();
+            assert_eq!(c.current_version, 1);
+            assert_eq!(c.version_history.len(), 1);
+            assert_eq!(c.owner, Principal::from_slice(&[1]));
+
+            // "abc", "new" and "hello", each once
+            assert_eq!(storage.storage_usage(), 3 + 3 + 5);
+            assert_eq!(storage.file_chunks.len(), 3);
+        });
+
+        let emptied: StableBTreeMap<Vec<u8>, Vec<u8>, Memory> =
+            StableBTreeMap::init(get_memory(LEGACY_CHUNKS_MEMORY_ID));
+        assert_eq!(emptied.len(), 0);
+    }
+}
@@ -0,0 +1,288 @@
+// Partial metadata updates. Edits to a file's descriptive metadata (path, type, tags,
+// attributes, expiry) are patches: a patch is validated as a whole before anything is
+// stored, and what it changed is recorded in the file's metadata history. Other changes bump the revision
+// without an entry there: uploads and new versions, controller retagging, shares,
+// retention, legal hold (which has its own audit trail) and restores from the trash.
+use crate::attributes::{valid_attribute_key, validate_attribute, FileAttribute, MAX_ATTRIBUTES};
+use crate::expiry::validate_expiry;
+use crate::folders::normalize_entry_path;
+use crate::{
+    authorize_access, check_revision, touch, FileMetadata, FileStorage, ShareRole, StorageError,
+    STATE,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::update;
+use std::collections::HashSet;
+
+const MAX_TAGS: usize = 32;
+const MAX_TAG_LENGTH: usize = 64;
+const MAX_FILE_TYPE_LENGTH: usize = 255;
+// Changes kept per file; older ones are dropped
+const MAX_METADATA_HISTORY: usize = 20;
+
+// Fields left out (null) stay unchanged
+#[derive(CandidType, Clone, Default, Deserialize)]
+pub(crate) struct MetadataPatch {
+    pub(crate) name: Option<String>, // New path; moving needs Admin rights
+    pub(crate) file_type: Option<String>,
+    pub(crate) is_encrypted: Option<bool>,
+    pub(crate) tags: Option<Vec<String>>, // Replaces all tags, before add/remove apply
+    pub(crate) add_tags: Option<Vec<String>>,
+    pub(crate) remove_tags: Option<Vec<String>>,
+    pub(crate) set_attributes: Option<Vec<FileAttribute>>,
+    pub(crate) remove_attributes: Option<Vec<String>>,
+    pub(crate) expires_at: Option<Option<u64>>, // Some(None) removes the expiry
+}
+
+// One entry in a file's metadata history
+#[derive(CandidType, Clone, Deserialize)]
+pub(crate) struct MetadataChange {
+    revision: u64, // Revision the change produced
+    author: Principal,
+    changed_at: u64,
+    patch: MetadataPatch, // What changed, as add/remove lists; `tags` is never set
+}
+
+pub(crate) fn validate_tag(tag: &str) -> Result<(), StorageError> {
+    if tag.is_empty() || tag.len() > MAX_TAG_LENGTH || tag.chars().any(char::is_control) {
+        return Err(StorageError::InvalidOperation);
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_file_type(file_type: &str) -> Result<(), StorageError> {
+    if file_type.len() > MAX_FILE_TYPE_LENGTH || file_type.chars().any(char::is_control) {
+        return Err(StorageError::InvalidFileType);
+    }
+    Ok(())
+}
+
+// Validates the tags given with an upload, dropping duplicates
+pub(crate) fn validate_tags(tags: Vec<String>) -> Result<Vec<String>, StorageError> {
+    let mut unique: Vec<String> = Vec::with_capacity(tags.len());
+    for tag in tags {
+        validate_tag(&tag)?;
+        if !unique.contains(&tag) {
+            unique.push(tag);
+        }
+    }
+    if unique.len() > MAX_TAGS {
+        return Err(StorageError::InvalidOperation);
+    }
+    Ok(unique)
+}
+
+// None for an empty list, so the history leaves out what did not change
+fn non_empty<T>(items: Vec<T>) -> Option<Vec<T>> {
+    if items.is_empty() {
+        None
+    } else {
+        Some(items)
+    }
+}
+
+impl MetadataPatch {
+    // Rejects lists longer than a file could hold and names no tag or attribute could
+    // have, so the size of a patch is bounded before any of it is applied
+    fn validate_sizes(&self) -> Result<(), StorageError> {
+        let tag_lists = [&self.tags, &self.add_tags, &self.remove_tags];
+        let too_long = tag_lists
+            .iter()
+            .any(|tags| tags.as_ref().is_some_and(|tags| tags.len() > MAX_TAGS))
+            || self
+                .set_attributes
+                .as_ref()
+                .is_some_and(|attributes| attributes.len() > MAX_ATTRIBUTES)
+            || self
+                .remove_attributes
+                .as_ref()
+                .is_some_and(|keys| keys.len() > MAX_ATTRIBUTES);
+        if too_long {
+            return Err(StorageError::InvalidOperation);
+        }
+        for tags in tag_lists.into_iter().flatten() {
+            tags.iter().try_for_each(|tag| validate_tag(tag))?;
+        }
+        let remove_attributes = self.remove_attributes.as_deref().unwrap_or_default();
+        if !remove_attributes.iter().all(|key| valid_attribute_key(key)) {
+            return Err(StorageError::InvalidOperation);
+        }
+        Ok(())
+    }
+
+    // The change from `before` to `after`, as a patch that would make it
+    fn effective(before: &FileMetadata, after: &FileMetadata) -> MetadataPatch {
+        MetadataPatch {
+            name: Some(after.name.clone()).filter(|name| *name != before.name),
+            file_type: Some(after.file_type.clone())
+                .filter(|file_type| *file_type != before.file_type),
+            is_encrypted: Some(after.is_encrypted).filter(|flag| *flag != before.is_encrypted),
+            tags: None,
+            add_tags: non_empty(
+                after
+                    .tags
+                    .iter()
+                    .filter(|tag| !before.tags.contains(tag))
+                    .cloned()
+                    .collect(),
+            ),
+            remove_tags: non_empty(
+                before
+                    .tags
+                    .iter()
+                    .filter(|tag| !after.tags.contains(tag))
+                    .cloned()
+                    .collect(),
+            ),
+            set_attributes: non_empty(
+                after
+                    .attributes
+                    .iter()
+                    .filter(|attribute| !attribute.matches(before))
+                    .cloned()
+                    .collect(),
+            ),
+            remove_attributes: non_empty(
+                before
+                    .attributes
+                    .iter()
+                    .map(|attribute| &attribute.key)
+                    .filter(|key| !after.attributes.iter().any(|entry| &entry.key == *key))
+                    .cloned()
+                    .collect(),
+            ),
+            expires_at: Some(after.expires_at)
+                .filter(|expires_at| *expires_at != before.expires_at),
+        }
+    }
+
+    // Applies every field but the name to `metadata`. On error `metadata` may be
+    // partly changed and must be discarded.
+    fn apply(&self, metadata: &mut FileMetadata) -> Result<(), StorageError> {
+        self.validate_sizes()?;
+        if let Some(file_type) = &self.file_type {
+            validate_file_type(file_type)?;
+            metadata.file_type = file_type.clone();
+        }
+        if let Some(is_encrypted) = self.is_encrypted {
+            metadata.is_encrypted = is_encrypted;
+        }
+        if let Some(expires_at) = self.expires_at {
+            validate_expiry(expires_at)?;
+            metadata.expires_at = expires_at;
+        }
+
+        let add_tags = self.add_tags.as_deref().unwrap_or_default();
+        let remove_tags = self.remove_tags.as_deref().unwrap_or_default();
+        if add_tags.iter().any(|tag| remove_tags.contains(tag)) {
+            return Err(StorageError::InvalidOperation);
+        }
+        if let Some(tags) = &self.tags {
+            metadata.tags.clear();
+            for tag in tags {
+                if !metadata.tags.contains(tag) {
+                    metadata.tags.push(tag.clone());
+                }
+            }
+        }
+        metadata.tags.retain(|tag| !remove_tags.contains(tag));
+        for tag in add_tags {
+            if !metadata.tags.contains(tag) {
+                metadata.tags.push(tag.clone());
+            }
+        }
+        if (self.tags.is_some() || !add_tags.is_empty()) && metadata.tags.len() > MAX_TAGS {
+            return Err(StorageError::InvalidOperation);
+        }
+
+        let set_attributes = self.set_attributes.as_deref().unwrap_or_default();
+        let remove_attributes = self.remove_attributes.as_deref().unwrap_or_default();
+        let mut keys = HashSet::new();
+        for attribute in set_attributes {
+            validate_attribute(attribute)?;
+            if !keys.insert(&attribute.key) {
+                return Err(StorageError::InvalidOperation);
+            }
+        }
+        for key in remove_attributes {
+            if !keys.insert(key) {
+                return Err(StorageError::InvalidOperation);
+            }
+        }
+        metadata
+            .attributes
+            .retain(|entry| !keys.contains(&entry.key));
+        metadata.attributes.extend_from_slice(set_attributes);
+        if metadata.attributes.len() > MAX_ATTRIBUTES {
+            return Err(StorageError::InvalidOperation);
+        }
+        metadata.attributes.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(())
+    }
+}
+
+impl FileStorage {
+    // Validates and applies a patch to a file. Nothing changes unless all of it applies.
+    pub(crate) fn patch_metadata(
+        &mut self,
+        caller: Principal,
+        name: &String,
+        patch: MetadataPatch,
+        expected_revision: Option<u64>,
+    ) -> Result<(), StorageError> {
+        let mut metadata = self.files.get(name).ok_or(StorageError::FileNotFound)?;
+        let destination = match &patch.name {
+            Some(destination) => Some(normalize_entry_path(destination)?),
+            None => None,
+        }
+        .filter(|destination| destination != name);
+
+        let required = if destination.is_some() {
+            ShareRole::Admin
+        } else {
+            ShareRole::Write
+        };
+        authorize_access(caller, &metadata, required)?;
+        check_revision(&metadata, expected_revision)?;
+
+        let before = metadata.clone();
+        patch.apply(&mut metadata)?;
+        self.check_retention_kept(&before, &metadata)?;
+        if let Some(destination) = destination {
+            self.move_metadata(caller, &mut metadata, destination)?;
+        }
+
+        let change = MetadataPatch::effective(&before, &metadata);
+        touch(&mut metadata);
+        metadata.metadata_history.push(MetadataChange {
+            revision: metadata.revision,
+            author: caller,
+            changed_at: metadata.last_modified,
+            patch: change,
+        });
+        let excess = metadata
+            .metadata_history
+            .len()
+            .saturating_sub(MAX_METADATA_HISTORY);
+        metadata.metadata_history.drain(..excess);
+
+        self.put_file(metadata);
+        Ok(())
+    }
+}
+
+// Changes any subset of a file's metadata in one call
+#[update]
+fn patch_file_metadata(
+    name: String,
+    patch: MetadataPatch,
+    expected_revision: Option<u64>,
+) -> Result<bool, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        state
+            .borrow_mut()
+            .patch_metadata(caller, &name, patch, expected_revision)?;
+        Ok(true)
+    })
+}
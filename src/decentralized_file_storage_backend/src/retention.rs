@@ -0,0 +1,248 @@
+// Write-once retention. A retained file cannot be deleted or have its content replaced
+// until its retention lapses; a file under legal hold not until the hold is cleared.
+// Retention comes from a date set on the file, or from a period set on one of its tags
+// and counted from the file's upload.
+use crate::listing::{page_limit, FilePosition, MAX_SCAN};
+use crate::patch::validate_tag;
+use crate::{
+    authorize_access, authorize_controller, get_current_timestamp, touch, FileMetadata,
+    FileStorage, ShareRole, StorageError, STATE,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+use std::ops::Bound;
+
+const MAX_REASON_LENGTH: usize = 1024;
+
+// One entry in the legal hold audit trail
+#[derive(CandidType, Clone, Deserialize)]
+pub(crate) struct LegalHoldEvent {
+    id: u64,
+    name: String,
+    held: bool, // Whether the hold was set or cleared
+    reason: String,
+    by: Principal,
+    at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct TagRetention {
+    tag: String,
+    period: u64, // Seconds after upload
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct RetentionStatus {
+    retained_until: Option<u64>, // Latest end of the file's own and its tags' retention
+    legal_hold: bool,
+}
+
+impl FileStorage {
+    // When the retention of a file ends, ignoring legal hold; None if it has none
+    fn retention_end(&self, metadata: &FileMetadata) -> Option<u64> {
+        let by_tag = metadata.tags.iter().filter_map(|tag| {
+            self.tag_retention
+                .get(tag)
+                .map(|period| metadata.upload_timestamp.saturating_add(period))
+        });
+        by_tag.chain(metadata.retain_until).max()
+    }
+
+    // Fails with RetentionActive while a file may not be deleted or overwritten
+    pub(crate) fn check_retention(&self, metadata: &FileMetadata) -> Result<(), StorageError> {
+        let retained = self
+            .retention_end(metadata)
+            .is_some_and(|end| end > get_current_timestamp());
+        if metadata.legal_hold || retained {
+            return Err(StorageError::RetentionActive);
+        }
+        Ok(())
+    }
+
+    // Fails with RetentionActive if a metadata change would shorten a retention that
+    // is still running, such as by removing a tag that carries one
+    pub(crate) fn check_retention_kept(
+        &self,
+        before: &FileMetadata,
+        after: &FileMetadata,
+    ) -> Result<(), StorageError> {
+        let now = get_current_timestamp();
+        let end_before = self.retention_end(before).filter(|end| *end > now);
+        if end_before > self.retention_end(after) {
+            return Err(StorageError::RetentionActive);
+        }
+        Ok(())
+    }
+
+    // Whether a file tagged `tag` is still retained by a tag rule of `period`. Only
+    // files uploaded within the period can be; if there are more than MAX_SCAN of
+    // them, assumes one is.
+    fn tag_retention_running(&self, tag: &str, period: u64) -> bool {
+        let now = get_current_timestamp();
+        let start = FilePosition {
+            value: now.saturating_sub(period),
+            name: String::new(),
+        };
+        for (scanned, position) in self.uploaded_index.keys_range(start..).enumerate() {
+            if scanned >= MAX_SCAN {
+                return true;
+            }
+            let retained = self.files.get(&position.name).is_some_and(|metadata| {
+                metadata.tags.iter().any(|file_tag| file_tag == tag)
+                    && metadata.upload_timestamp.saturating_add(period) > now
+            });
+            if retained {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Gives `to` the retention rule of `from` when its files are retagged, keeping the
+    // longer period if `to` has a rule already
+    pub(crate) fn move_tag_retention(&mut self, from: &str, to: &str) {
+        if let Some(period) = self.tag_retention.get(&from.to_string()) {
+            let current = self.tag_retention.get(&to.to_string()).unwrap_or(0);
+            self.tag_retention.insert(to.to_string(), period.max(current));
+        }
+    }
+
+    // Drops the retention rule of a tag no file carries anymore
+    pub(crate) fn drop_unused_tag_retention(&mut self, tag: &str) {
+        if self.tag_index.count(tag) == 0 {
+            self.tag_retention.remove(&tag.to_string());
+        }
+    }
+
+    fn log_legal_hold(&mut self, name: String, held: bool, reason: String, by: Principal) {
+        // The log is never trimmed, so its length is the next free id
+        let id = self.legal_hold_log.len();
+        self.legal_hold_log.insert(
+            id,
+            LegalHoldEvent {
+                id,
+                name,
+                held,
+                reason,
+                by,
+                at: get_current_timestamp(),
+            },
+        );
+    }
+}
+
+#[query]
+fn get_file_retention(name: String) -> Result<RetentionStatus, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let storage = state.borrow();
+        let metadata = storage.files.get(&name).ok_or(StorageError::FileNotFound)?;
+        authorize_access(caller, &metadata, ShareRole::Read)?;
+        Ok(RetentionStatus {
+            retained_until: storage.retention_end(&metadata),
+            legal_hold: metadata.legal_hold,
+        })
+    })
+}
+
+// Retains a file until `retain_until`. Retention can be extended but never shortened.
+#[update]
+fn set_file_retention(name: String, retain_until: u64) -> Result<bool, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+        let mut metadata = storage.files.get(&name).ok_or(StorageError::FileNotFound)?;
+        authorize_access(caller, &metadata, ShareRole::Admin)?;
+        if metadata.retain_until.is_some_and(|current| current > retain_until) {
+            return Err(StorageError::InvalidOperation);
+        }
+
+        metadata.retain_until = Some(retain_until);
+        touch(&mut metadata);
+        storage.put_file(metadata);
+        Ok(true)
+    })
+}
+
+// Retains every file tagged `tag` for `period` seconds after its upload, or removes
+// the rule. Rules apply to the tag itself, not to its descendants. A rule can only be
+// shortened or removed once no file is retained by it anymore.
+#[update]
+fn set_tag_retention(tag: String, period: Option<u64>) -> Result<bool, StorageError> {
+    authorize_controller()?;
+    validate_tag(&tag)?;
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+        if let Some(current) = storage.tag_retention.get(&tag) {
+            let shortened = period.is_none_or(|period| period < current);
+            if shortened && storage.tag_retention_running(&tag, current) {
+                return Err(StorageError::RetentionActive);
+            }
+        }
+        match period {
+            Some(period) => storage.tag_retention.insert(tag, period),
+            None => storage.tag_retention.remove(&tag),
+        };
+        Ok(true)
+    })
+}
+
+#[query]
+fn list_tag_retention() -> Result<Vec<TagRetention>, StorageError> {
+    authorize_controller()?;
+    STATE.with(|state| {
+        Ok(state
+            .borrow()
+            .tag_retention
+            .iter()
+            .map(|(tag, period)| TagRetention { tag, period })
+            .collect())
+    })
+}
+
+// Sets or clears the legal hold on a file and records the change in the audit trail
+#[update]
+fn set_legal_hold(name: String, held: bool, reason: String) -> Result<bool, StorageError> {
+    authorize_controller()?;
+    if reason.len() > MAX_REASON_LENGTH {
+        return Err(StorageError::InvalidOperation);
+    }
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+        let mut metadata = storage.files.get(&name).ok_or(StorageError::FileNotFound)?;
+        if metadata.legal_hold == held {
+            return Ok(false);
+        }
+
+        metadata.legal_hold = held;
+        touch(&mut metadata);
+        storage.put_file(metadata);
+        storage.log_legal_hold(name, held, reason, caller);
+        Ok(true)
+    })
+}
+
+// The legal hold audit trail, oldest first. Page through with `after` set to the last
+// id returned.
+#[query]
+fn list_legal_hold_events(
+    after: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Vec<LegalHoldEvent>, StorageError> {
+    authorize_controller()?;
+    let limit = page_limit(limit);
+    let start = match after {
+        Some(id) => Bound::Excluded(id),
+        None => Bound::Unbounded,
+    };
+    STATE.with(|state| {
+        Ok(state
+            .borrow()
+            .legal_hold_log
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(_, event)| event)
+            .collect())
+    })
+}
@@ -0,0 +1,316 @@
+// Metadata search. Where possible a query is answered from the inverted indexes: its
+// most selective indexed term supplies candidates in name order and the whole query is
+// checked on each candidate. Queries without an indexed term walk a range of a sort
+// index if they constrain size or time, and every file otherwise.
+use crate::attributes::{attribute_index_key, AttributeRange, FileAttribute};
+use crate::listing::{collect_page, page_limit, FilePage, FilePosition, FileSortKey};
+use crate::{FileMetadata, FileStorage, StorageError, STATE};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::query;
+use std::collections::HashSet;
+use std::iter::Peekable;
+use std::ops::Bound;
+
+// Bounds on query size, so a single query cannot exhaust the instruction limit
+const MAX_QUERY_TERMS: usize = 64;
+const MAX_QUERY_DEPTH: usize = 8;
+
+// Inclusive bounds; a missing bound is open
+#[derive(CandidType, Clone, Deserialize)]
+pub(crate) struct ValueRange {
+    min: Option<u64>,
+    max: Option<u64>,
+}
+
+impl ValueRange {
+    fn contains(&self, value: u64) -> bool {
+        self.min.is_none_or(|min| value >= min) && self.max.is_none_or(|max| value <= max)
+    }
+}
+
+#[derive(CandidType, Clone, Deserialize)]
+pub(crate) enum FileQuery {
+    And(Vec<FileQuery>), // An empty And matches every file
+    Or(Vec<FileQuery>),  // An empty Or matches nothing
+    Not(Box<FileQuery>),
+    Tag(String),
+    FileType(String), // Exact type, or a prefix ending in `*` such as "image/*"
+    Size(ValueRange),
+    UploadTimestamp(ValueRange),
+    LastModified(ValueRange),
+    NamePrefix(String),
+    NameContains(String),
+    Owner(Principal),
+    Encrypted(bool),
+    Attribute(FileAttribute), // The file has the attribute with exactly this value
+    AttributeRange(AttributeRange),
+}
+
+// The prefix of a wildcard file type pattern, or None for an exact type
+fn file_type_prefix(pattern: &str) -> Option<&str> {
+    pattern.strip_suffix('*')
+}
+
+impl FileQuery {
+    pub(crate) fn matches(&self, metadata: &FileMetadata) -> bool {
+        match self {
+            FileQuery::And(queries) => queries.iter().all(|query| query.matches(metadata)),
+            FileQuery::Or(queries) => queries.iter().any(|query| query.matches(metadata)),
+            FileQuery::Not(query) => !query.matches(metadata),
+            FileQuery::Tag(tag) => metadata.tags.contains(tag),
+            FileQuery::FileType(pattern) => match file_type_prefix(pattern) {
+                Some(prefix) => metadata.file_type.starts_with(prefix),
+                None => metadata.file_type == *pattern,
+            },
+            FileQuery::Size(range) => range.contains(metadata.size as u64),
+            FileQuery::UploadTimestamp(range) => range.contains(metadata.upload_timestamp),
+            FileQuery::LastModified(range) => range.contains(metadata.last_modified),
+            FileQuery::NamePrefix(prefix) => metadata.name.starts_with(prefix.as_str()),
+            FileQuery::NameContains(text) => metadata.name.contains(text.as_str()),
+            FileQuery::Owner(owner) => metadata.owner == *owner,
+            FileQuery::Encrypted(is_encrypted) => metadata.is_encrypted == *is_encrypted,
+            FileQuery::Attribute(attribute) => attribute.matches(metadata),
+            FileQuery::AttributeRange(range) => range.matches(metadata),
+        }
+    }
+
+    // Rejects queries nested too deeply or with too many terms; returns the term count
+    pub(crate) fn validate(&self, depth: usize) -> Result<usize, StorageError> {
+        if depth > MAX_QUERY_DEPTH {
+            return Err(StorageError::InvalidOperation);
+        }
+        let mut terms = 1;
+        match self {
+            FileQuery::And(queries) | FileQuery::Or(queries) => {
+                for query in queries {
+                    terms += query.validate(depth + 1)?;
+                    if terms > MAX_QUERY_TERMS {
+                        return Err(StorageError::InvalidOperation);
+                    }
+                }
+            }
+            FileQuery::Not(query) => terms += query.validate(depth + 1)?,
+            _ => {}
+        }
+        if terms > MAX_QUERY_TERMS {
+            return Err(StorageError::InvalidOperation);
+        }
+        Ok(terms)
+    }
+}
+
+// The first size or time term of a query or of its top-level conjunction
+fn range_term(query: &FileQuery) -> Option<(FileSortKey, &ValueRange)> {
+    match query {
+        FileQuery::Size(range) => Some((FileSortKey::Size, range)),
+        FileQuery::UploadTimestamp(range) => Some((FileSortKey::UploadTimestamp, range)),
+        FileQuery::LastModified(range) => Some((FileSortKey::LastModified, range)),
+        FileQuery::And(queries) => queries.iter().find_map(range_term),
+        _ => None,
+    }
+}
+
+type Names<'a> = Box<dyn Iterator<Item = String> + 'a>;
+
+// Merges streams of names, each in name order, into one without duplicates
+struct Union<'a> {
+    streams: Vec<Peekable<Names<'a>>>,
+}
+
+impl Iterator for Union<'_> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let next = self
+            .streams
+            .iter_mut()
+            .filter_map(|stream| stream.peek().cloned())
+            .min()?;
+        for stream in &mut self.streams {
+            if stream.peek() == Some(&next) {
+                stream.next();
+            }
+        }
+        Some(next)
+    }
+}
+
+fn distinct_tags(metadata: &FileMetadata) -> HashSet<&String> {
+    metadata.tags.iter().collect()
+}
+
+impl FileStorage {
+    pub(crate) fn index_postings(&mut self, metadata: &FileMetadata) {
+        for tag in distinct_tags(metadata) {
+            self.tag_index.insert(tag, &metadata.name);
+        }
+        self.type_index.insert(&metadata.file_type, &metadata.name);
+        self.owner_index
+            .insert(&metadata.owner.to_text(), &metadata.name);
+        for attribute in &metadata.attributes {
+            self.attribute_index
+                .insert(&attribute_index_key(attribute), &metadata.name);
+        }
+    }
+
+    pub(crate) fn unindex_postings(&mut self, metadata: &FileMetadata) {
+        for tag in distinct_tags(metadata) {
+            self.tag_index.remove(tag, &metadata.name);
+        }
+        self.type_index.remove(&metadata.file_type, &metadata.name);
+        self.owner_index
+            .remove(&metadata.owner.to_text(), &metadata.name);
+        for attribute in &metadata.attributes {
+            self.attribute_index
+                .remove(&attribute_index_key(attribute), &metadata.name);
+        }
+    }
+
+    // Candidate names for `query` from the inverted indexes, in name order and starting
+    // after `after`, with an upper bound on their number. The candidates include every
+    // matching file but may include others. None if no index applies.
+    fn plan(&self, query: &FileQuery, after: Option<&String>) -> Option<(u64, Names<'_>)> {
+        match query {
+            FileQuery::And(queries) => queries
+                .iter()
+                .filter_map(|query| self.plan(query, after))
+                .min_by_key(|(estimate, _)| *estimate),
+            FileQuery::Or(queries) => {
+                let mut estimate = 0;
+                let mut streams = Vec::new();
+                for query in queries {
+                    let (bound, names) = self.plan(query, after)?;
+                    estimate += bound;
+                    streams.push(names.peekable());
+                }
+                Some((estimate, Box::new(Union { streams })))
+            }
+            FileQuery::Tag(tag) => Some((
+                self.tag_index.count(tag),
+                Box::new(self.tag_index.names(tag, after)),
+            )),
+            FileQuery::FileType(pattern) => match file_type_prefix(pattern) {
+                Some(prefix) => {
+                    let types: Vec<(String, u64)> =
+                        self.type_index.keys_with_prefix(prefix).collect();
+                    let estimate = types.iter().map(|(_, count)| count).sum();
+                    let streams = types
+                        .iter()
+                        .map(|(file_type, _)| {
+                            let names: Names = Box::new(self.type_index.names(file_type, after));
+                            names.peekable()
+                        })
+                        .collect();
+                    Some((estimate, Box::new(Union { streams })))
+                }
+                None => Some((
+                    self.type_index.count(pattern),
+                    Box::new(self.type_index.names(pattern, after)),
+                )),
+            },
+            FileQuery::Owner(owner) => {
+                let owner = owner.to_text();
+                Some((
+                    self.owner_index.count(&owner),
+                    Box::new(self.owner_index.names(&owner, after)),
+                ))
+            }
+            FileQuery::Attribute(attribute) => {
+                let key = attribute_index_key(attribute);
+                Some((
+                    self.attribute_index.count(&key),
+                    Box::new(self.attribute_index.names(&key, after)),
+                ))
+            }
+            FileQuery::NamePrefix(prefix) => {
+                let start = match after {
+                    Some(name) if name >= prefix => Bound::Excluded(name.clone()),
+                    _ => Bound::Included(prefix.clone()),
+                };
+                let prefix = prefix.clone();
+                let names = self
+                    .files
+                    .keys_range((start, Bound::Unbounded))
+                    .take_while(move |name| name.starts_with(&prefix));
+                Some((self.files.len(), Box::new(names)))
+            }
+            _ => None,
+        }
+    }
+
+    // Positions of every file that may match `query`, starting after `cursor`
+    pub(crate) fn candidates(
+        &self,
+        query: &FileQuery,
+        cursor: Option<FilePosition>,
+    ) -> Box<dyn Iterator<Item = FilePosition> + '_> {
+        let after = cursor.as_ref().map(|cursor| &cursor.name);
+        let ranged = range_term(query)
+            .and_then(|(sort_by, range)| Some((self.sort_index(sort_by)?, range)));
+
+        match (self.plan(query, after), ranged) {
+            (Some((_, names)), _) => Box::new(names.map(|name| FilePosition { value: 0, name })),
+            (None, Some((index, range))) => {
+                let start = match cursor {
+                    Some(cursor) => Bound::Excluded(cursor),
+                    None => Bound::Included(FilePosition {
+                        value: range.min.unwrap_or(0),
+                        name: String::new(),
+                    }),
+                };
+                let max = range.max.unwrap_or(u64::MAX);
+                Box::new(
+                    index
+                        .keys_range((start, Bound::Unbounded))
+                        .take_while(move |position| position.value <= max),
+                )
+            }
+            (None, None) => self.positions(FileSortKey::Name, false, cursor),
+        }
+    }
+
+    // One page of the readable files matching `query`
+    fn search(
+        &self,
+        caller: Principal,
+        query: &FileQuery,
+        cursor: Option<FilePosition>,
+        limit: usize,
+    ) -> FilePage {
+        let positions = self.candidates(query, cursor);
+        collect_page(self, caller, positions, limit, |metadata| query.matches(metadata))
+    }
+}
+
+// Files carrying every one of `tags` or one of its descendants in the tag hierarchy,
+// in name order and without content. Paged like list_files; no tags matches every
+// file the caller can read.
+#[query]
+fn search_by_tags(
+    tags: Vec<String>,
+    cursor: Option<FilePosition>,
+    limit: Option<u32>,
+) -> FilePage {
+    let caller = ic_cdk::caller();
+    let limit = page_limit(limit);
+    STATE.with(|state| {
+        let storage = state.borrow();
+        let query = FileQuery::And(tags.iter().map(|tag| storage.tag_query(tag)).collect());
+        storage.search(caller, &query, cursor, limit)
+    })
+}
+
+// Readable files matching a structured query, without content. Paged like list_files;
+// results are in name order, or in order of the constrained value when the query is
+// only narrowed by size or time.
+#[query]
+fn search_files(
+    query: FileQuery,
+    cursor: Option<FilePosition>,
+    limit: Option<u32>,
+) -> Result<FilePage, StorageError> {
+    query.validate(0)?;
+    let caller = ic_cdk::caller();
+    let limit = page_limit(limit);
+    STATE.with(|state| Ok(state.borrow().search(caller, &query, cursor, limit)))
+}
@@ -0,0 +1,217 @@
+// Tag administration across all files, and a tag hierarchy: every tag may have one
+// parent, and searching for a tag also finds files tagged with its descendants. Tag
+// administration affects every file, so it is reserved to controllers.
+use crate::listing::page_limit;
+use crate::patch::validate_tag;
+use crate::search::FileQuery;
+use crate::{authorize_controller, touch, FileStorage, StorageError, STATE};
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use std::collections::{HashSet, VecDeque};
+
+// Files retagged per call, so updates stay within the instruction limit
+const MAX_TAG_BATCH: usize = 1000;
+// Tags a search for one tag expands to, itself included
+const MAX_EXPANDED_TAGS: usize = 64;
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct TagInfo {
+    tag: String,
+    count: u64,
+    parent: Option<String>,
+}
+
+impl FileStorage {
+    // Replaces `from` by `to` in the tags of up to `budget` files, or removes it if `to`
+    // is None. Returns the number of files changed. Fails with RetentionActive, changing
+    // nothing, if that would shorten the retention of one of the files.
+    fn retag_files(
+        &mut self,
+        from: &str,
+        to: Option<&str>,
+        budget: usize,
+    ) -> Result<usize, StorageError> {
+        let names: Vec<String> = self.tag_index.names(from, None).take(budget).collect();
+        let mut retagged = Vec::with_capacity(names.len());
+        for name in &names {
+            if let Some(before) = self.files.get(name) {
+                let mut metadata = before.clone();
+                metadata.tags.retain(|tag| tag != from);
+                if let Some(to) = to {
+                    if !metadata.tags.iter().any(|tag| tag == to) {
+                        metadata.tags.push(to.to_string());
+                    }
+                }
+                self.check_retention_kept(&before, &metadata)?;
+                touch(&mut metadata);
+                retagged.push(metadata);
+            }
+        }
+        for metadata in retagged {
+            self.put_file(metadata);
+        }
+        Ok(names.len())
+    }
+
+    fn tag_parent(&self, tag: &str) -> Option<String> {
+        self.tag_parents.get(&tag.to_string())
+    }
+
+    // Whether `ancestor` is `tag` or above it in the hierarchy
+    fn is_ancestor(&self, ancestor: &str, tag: &str) -> bool {
+        let mut visited = HashSet::new();
+        let mut current = Some(tag.to_string());
+        while let Some(tag) = current {
+            if tag == ancestor {
+                return true;
+            }
+            if !visited.insert(tag.clone()) {
+                return false;
+            }
+            current = self.tag_parent(&tag);
+        }
+        false
+    }
+
+    // Sets or clears the parent of `tag` without checking for cycles
+    fn link_tag(&mut self, tag: &str, parent: Option<&str>) {
+        if let Some(previous) = self.tag_parents.remove(&tag.to_string()) {
+            self.tag_children.remove(&previous, tag);
+        }
+        if let Some(parent) = parent {
+            self.tag_parents.insert(tag.to_string(), parent.to_string());
+            self.tag_children.insert(parent, tag);
+        }
+    }
+
+    // Takes `tag` out of the hierarchy. Its children move to `heir` where that does not
+    // create a cycle, and otherwise become top-level tags.
+    fn detach_tag(&mut self, tag: &str, heir: Option<&str>) {
+        let children: Vec<String> = self.tag_children.names(tag, None).collect();
+        for child in children {
+            let heir = heir.filter(|heir| !self.is_ancestor(&child, heir));
+            self.link_tag(&child, heir);
+        }
+        self.link_tag(tag, None);
+    }
+
+    // `tag` and its descendants, breadth first, up to MAX_EXPANDED_TAGS
+    fn expand_tag(&self, tag: &str) -> Vec<String> {
+        let mut tags = vec![tag.to_string()];
+        let mut queue = VecDeque::from([tag.to_string()]);
+        while let Some(parent) = queue.pop_front() {
+            for child in self.tag_children.names(&parent, None) {
+                if tags.len() >= MAX_EXPANDED_TAGS {
+                    return tags;
+                }
+                queue.push_back(child.clone());
+                tags.push(child);
+            }
+        }
+        tags
+    }
+
+    // Query for files carrying `tag` or one of its descendants
+    pub(crate) fn tag_query(&self, tag: &str) -> FileQuery {
+        let tags = self.expand_tag(tag);
+        if tags.len() == 1 {
+            FileQuery::Tag(tag.to_string())
+        } else {
+            FileQuery::Or(tags.into_iter().map(FileQuery::Tag).collect())
+        }
+    }
+
+    // Merges `sources` into `target`, retagging at most MAX_TAG_BATCH files. The target
+    // takes over the sources' retention rules, so no file's retention is shortened, and
+    // a source's place in the hierarchy if it has none of its own.
+    fn merge_into(&mut self, sources: &[String], target: &str) -> Result<u64, StorageError> {
+        validate_tag(target)?;
+        let mut budget = MAX_TAG_BATCH;
+        for source in sources.iter().filter(|source| *source != target) {
+            if self.tag_parent(target).is_none() {
+                let parent = self
+                    .tag_parent(source)
+                    .filter(|parent| !self.is_ancestor(target, parent));
+                if let Some(parent) = parent {
+                    self.link_tag(target, Some(&parent));
+                }
+            }
+            self.detach_tag(source, Some(target));
+            self.move_tag_retention(source, target);
+            budget -= self.retag_files(source, Some(target), budget)?;
+            self.drop_unused_tag_retention(source);
+            if budget == 0 {
+                break;
+            }
+        }
+        Ok((MAX_TAG_BATCH - budget) as u64)
+    }
+}
+
+// Every tag in use with the number of files carrying it and its parent, in tag order.
+// Page through with `after` set to the last tag returned.
+#[query]
+fn list_tags(after: Option<String>, limit: Option<u32>) -> Result<Vec<TagInfo>, StorageError> {
+    authorize_controller()?;
+    let limit = page_limit(limit);
+    STATE.with(|state| {
+        let storage = state.borrow();
+        Ok(storage
+            .tag_index
+            .keys_after(after.as_ref())
+            .take(limit)
+            .map(|(tag, count)| TagInfo {
+                parent: storage.tag_parent(&tag),
+                tag,
+                count,
+            })
+            .collect())
+    })
+}
+
+// The retagging endpoints change at most 1000 files per call and return how many they
+// changed; repeat the call until it returns 0.
+#[update]
+fn rename_tag(from: String, to: String) -> Result<u64, StorageError> {
+    authorize_controller()?;
+    STATE.with(|state| state.borrow_mut().merge_into(&[from], &to))
+}
+
+#[update]
+fn merge_tags(sources: Vec<String>, target: String) -> Result<u64, StorageError> {
+    authorize_controller()?;
+    STATE.with(|state| state.borrow_mut().merge_into(&sources, &target))
+}
+
+#[update]
+fn delete_tag(tag: String) -> Result<u64, StorageError> {
+    authorize_controller()?;
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+        let retagged = storage.retag_files(&tag, None, MAX_TAG_BATCH)?;
+        storage.detach_tag(&tag, None);
+        storage.drop_unused_tag_retention(&tag);
+        Ok(retagged as u64)
+    })
+}
+
+// Places `tag` under `parent` in the hierarchy, or makes it top-level. Fails with
+// InvalidOperation if this would create a cycle.
+#[update]
+fn set_tag_parent(tag: String, parent: Option<String>) -> Result<bool, StorageError> {
+    authorize_controller()?;
+    validate_tag(&tag)?;
+    if let Some(parent) = &parent {
+        validate_tag(parent)?;
+    }
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+        if let Some(parent) = &parent {
+            if storage.is_ancestor(&tag, parent) {
+                return Err(StorageError::InvalidOperation);
+            }
+        }
+        storage.link_tag(&tag, parent.as_deref());
+        Ok(true)
+    })
+}
@@ -0,0 +1,237 @@
+// Deleted files go to the trash first. They keep their chunks, and so their share of
+// storage usage, until they are purged: by empty_trash, or by a timer once the
+// retention period has passed.
+use crate::listing::{page_limit, MAX_SCAN};
+use crate::{
+    authorize_access, authorize_controller, can_access, get_current_timestamp, http, touch,
+    FileMetadata, FileStorage, ShareRole, StorageError, STATE,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+use std::ops::Bound;
+use std::time::Duration;
+
+pub(crate) const DEFAULT_TRASH_RETENTION: u64 = 30 * 24 * 60 * 60; // 30 days, in seconds
+// How often the timer looks for expired trash
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Files purged per call or timer tick, so purging stays within the instruction limit
+const MAX_PURGE_BATCH: usize = 1000;
+
+// A deleted file as held in the trash
+#[derive(CandidType, Clone, Deserialize)]
+pub(crate) struct TrashedFile {
+    pub(crate) metadata: FileMetadata,
+    pub(crate) deleted_at: u64,
+    pub(crate) deleted_by: Principal,
+}
+
+// What list_trash shows of a trashed file
+#[derive(CandidType, Deserialize)]
+pub(crate) struct TrashEntry {
+    id: u64,
+    name: String,
+    size: usize,
+    owner: Principal,
+    deleted_at: u64,
+    deleted_by: Principal,
+    purge_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct TrashPage {
+    entries: Vec<TrashEntry>,
+    next_cursor: Option<u64>, // Last id examined; None once the trash is walked through
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct EmptyTrashResult {
+    purged: u64,
+    next_cursor: Option<u64>, // Where the next call resumes; None once done
+}
+
+impl FileStorage {
+    fn trash_retention(&self) -> u64 {
+        self.stats.get().trash_retention
+    }
+
+    fn next_trash_id(&mut self) -> u64 {
+        let mut stats = self.stats.get().clone();
+        let id = stats.next_trash_id;
+        stats.next_trash_id += 1;
+        self.stats.set(stats).expect("failed to persist storage stats");
+        id
+    }
+
+    // Moves a file to the trash. Its chunks stay referenced until it is purged.
+    pub(crate) fn trash_file(&mut self, caller: Principal, name: &String) -> Option<u64> {
+        let metadata = self.take_file(name)?;
+        http::uncertify_file(name);
+        let id = self.next_trash_id();
+        self.trash.insert(
+            id,
+            TrashedFile {
+                metadata,
+                deleted_at: get_current_timestamp(),
+                deleted_by: caller,
+            },
+        );
+        Some(id)
+    }
+
+    // Walks the trash after `after`, collecting up to `limit` files the caller may
+    // restore. Stops after MAX_SCAN entries, so callers with little in the trash do not
+    // decode everyone else's; the returned cursor resumes the walk.
+    fn restorable(
+        &self,
+        caller: Principal,
+        after: Option<u64>,
+        limit: usize,
+    ) -> (Vec<(u64, TrashedFile)>, Option<u64>) {
+        let start = match after {
+            Some(id) => Bound::Excluded(id),
+            None => Bound::Unbounded,
+        };
+        let mut entries = self.trash.range((start, Bound::Unbounded)).peekable();
+        let mut found = Vec::new();
+        let mut last = None;
+        let mut scanned = 0;
+        while found.len() < limit && scanned < MAX_SCAN {
+            let (id, trashed) = match entries.next() {
+                Some(entry) => entry,
+                None => break,
+            };
+            scanned += 1;
+            last = Some(id);
+            if can_access(caller, &trashed.metadata, ShareRole::Admin) {
+                found.push((id, trashed));
+            }
+        }
+        let next_cursor = if entries.peek().is_some() { last } else { None };
+        (found, next_cursor)
+    }
+
+    // Removes a file from the trash for good, freeing chunks nothing else refers to
+    fn purge_trashed(&mut self, id: u64) {
+        if let Some(trashed) = self.trash.remove(&id) {
+            self.release_versions(&trashed.metadata);
+        }
+    }
+
+    // Purges up to MAX_PURGE_BATCH files whose retention has passed. Ids grow with
+    // deletion time, so the expired files are the oldest ids.
+    fn purge_expired(&mut self) -> usize {
+        let cutoff = get_current_timestamp().saturating_sub(self.trash_retention());
+        let expired: Vec<u64> = self
+            .trash
+            .iter()
+            .take_while(|(_, trashed)| trashed.deleted_at <= cutoff)
+            .take(MAX_PURGE_BATCH)
+            .map(|(id, _)| id)
+            .collect();
+        for id in &expired {
+            self.purge_trashed(*id);
+        }
+        expired.len()
+    }
+}
+
+// Tells the timer to purge expired trash periodically. Timers do not survive
+// upgrades, so this runs on init and post_upgrade.
+pub(crate) fn start_purge_timer() {
+    ic_cdk_timers::set_timer_interval(PURGE_INTERVAL, || {
+        STATE.with(|state| state.borrow_mut().purge_expired());
+    });
+}
+
+// Trashed files the caller may restore, oldest deletion first. A page may hold fewer
+// than `limit` entries, or none, while `next_cursor` is still set; page through with
+// `after` set to it until it is None.
+#[query]
+fn list_trash(after: Option<u64>, limit: Option<u32>) -> TrashPage {
+    let caller = ic_cdk::caller();
+    let limit = page_limit(limit);
+    STATE.with(|state| {
+        let storage = state.borrow();
+        let retention = storage.trash_retention();
+        let (found, next_cursor) = storage.restorable(caller, after, limit);
+        let entries = found
+            .into_iter()
+            .map(|(id, trashed)| TrashEntry {
+                id,
+                name: trashed.metadata.name,
+                size: trashed.metadata.size,
+                owner: trashed.metadata.owner,
+                deleted_at: trashed.deleted_at,
+                deleted_by: trashed.deleted_by,
+                purge_at: trashed.deleted_at.saturating_add(retention),
+            })
+            .collect();
+        TrashPage {
+            entries,
+            next_cursor,
+        }
+    })
+}
+
+// Puts a trashed file back at its original path, recreating missing parent folders.
+// Fails with FileAlreadyExists if something else has taken the path since.
+#[update]
+fn restore_file(id: u64) -> Result<bool, StorageError> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+        let trashed = storage.trash.get(&id).ok_or(StorageError::FileNotFound)?;
+        authorize_access(caller, &trashed.metadata, ShareRole::Admin)?;
+
+        let mut metadata = trashed.metadata;
+        storage.free_expired_name(&metadata.name)?;
+        if storage.files.contains_key(&metadata.name) {
+            return Err(StorageError::FileAlreadyExists);
+        }
+        storage.prepare_path(&metadata.name, caller)?;
+        storage.trash.remove(&id);
+
+        touch(&mut metadata);
+        http::certify_file(&metadata.name, &metadata.sha256);
+        storage.put_file(metadata);
+        Ok(true)
+    })
+}
+
+// Purges the trashed files the caller may restore, at most 1000 per call and looking
+// at most MAX_SCAN entries. Repeat the call with `after` set to `next_cursor` until
+// it is None.
+#[update]
+fn empty_trash(after: Option<u64>) -> EmptyTrashResult {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+        let (found, next_cursor) = storage.restorable(caller, after, MAX_PURGE_BATCH);
+        for (id, _) in &found {
+            storage.purge_trashed(*id);
+        }
+        EmptyTrashResult {
+            purged: found.len() as u64,
+            next_cursor,
+        }
+    })
+}
+
+#[query]
+fn get_trash_retention() -> u64 {
+    STATE.with(|state| state.borrow().trash_retention())
+}
+
+// Sets how many seconds deleted files stay in the trash. Applies to files already
+// there, which are purged by the next timer run if now past the new period.
+#[update]
+fn set_trash_retention(seconds: u64) -> Result<bool, StorageError> {
+    authorize_controller()?;
+    STATE.with(|state| {
+        let mut storage = state.borrow_mut();
+        let mut stats = storage.stats.get().clone();
+        stats.trash_retention = seconds;
+        storage.stats.set(stats).expect("failed to persist storage stats");
+        Ok(true)
+    })
+}